@@ -1,31 +1,177 @@
-use std::io::ErrorKind;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
 use std::num::ParseIntError;
-use std::time::{Duration, Instant};
-use std::{collections::HashMap, thread};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    thread,
+};
 
 use rand::prelude::*;
 
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 
-use structopt::StructOpt;
+use structopt::{clap, StructOpt};
 
 use pnet::datalink::Channel::Ethernet;
-use pnet::datalink::{self, ChannelType, Config};
+use pnet::datalink::{self, ChannelType, Config, FanoutOption, FanoutType, NetworkInterface};
 use pnet::packet::ethernet::{EtherType, MutableEthernetPacket};
-use pnet::packet::Packet;
+use pnet::packet::{MutablePacket, Packet};
 use pnet::util::MacAddr;
 
 // Ethernet Header Size: SRC(6) + DST(6) + EtherType(2) = 14
 const ETH_HEADER_SIZE: usize = 14;
 
-#[derive(StructOpt, Debug)]
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+// RX read timeout, a stream idle for this long is considered finished
+const RX_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Offset of the `last` flag in the serialized Id: ID(4) + CNT(8) = 12
+const ID_LAST_OFFSET: usize = 12;
+
+// Process start, for --setup-times
+static START: OnceLock<Instant> = OnceLock::new();
+
+fn since_start() -> f32 {
+    START.get_or_init(Instant::now).elapsed().as_secs_f32()
+}
+
+// Set while a --progress status line is drawn on stderr without a newline
+static PROGRESS_LINE: AtomicBool = AtomicBool::new(false);
+
+// Set by SIGUSR1 and cleared by SIGUSR2, see Pause. The signal handler keeps
+// the time so a loop blocked in a read still learns how long the pause was.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static PAUSED_AT_NS: AtomicU64 = AtomicU64::new(0); // Since START
+static PAUSED_NS: AtomicU64 = AtomicU64::new(0); // Of all finished pauses
+
+// How often a paused sender checks whether it may go on
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("Network interface not found: {0}")]
+    InterfaceNotFound(String),
+    #[error(
+        "VLAN sub-interface not found: {ifname}. Create it on {parent} with:\n  \
+         sudo ip link add link {parent} name {ifname} type vlan id {vid}\n  \
+         sudo ip link set {ifname} up"
+    )]
+    VlanNotFound {
+        ifname: String,
+        parent: String,
+        vid: u16,
+    },
+    #[error("An error occurred when creating the datalink channel on {ifname}: {source}")]
+    ChannelOpen { ifname: String, source: io::Error },
+    #[error(
+        "Opening {ifname}: {source}\nRaw sockets need the CAP_NET_RAW capability. Run as \
+         root, or grant it once per build with:\n  sudo setcap cap_net_raw+ep {}",
+        exe_path()
+    )]
+    PermissionDenied { ifname: String, source: io::Error },
+    #[error("Failed to set up the TX socket, {what}: {source}")]
+    Socket {
+        what: &'static str,
+        source: io::Error,
+    },
+    #[error("An error occurred while sending: {0}")]
+    Send(io::Error),
+    #[error("An error occurred while reading: {0}")]
+    Receive(io::Error),
+    #[error("malformed frame of {0} bytes")]
+    MalformedFrame(usize),
+    #[error("frame of Ether Type {0:x}")]
+    WrongEthertype(u16),
+    #[error("{0}")]
+    Config(String),
+    #[error("{}: {msg}", path.display())]
+    Import { path: PathBuf, msg: String },
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Failed to {what}: {source}")]
+    Io { what: String, source: io::Error },
+    #[error("Strict sequence check failed on stream {stream:x}: {msg}")]
+    StrictSequence { stream: u32, msg: String },
+    #[error("{0} failed")]
+    Thread(&'static str),
+}
+
+impl Error {
+    // Socket errors caused by missing privileges get their own variant
+    fn channel(ifname: &str, source: io::Error) -> Self {
+        let ifname = ifname.to_string();
+        if source.kind() == ErrorKind::PermissionDenied {
+            Error::PermissionDenied { ifname, source }
+        } else {
+            Error::ChannelOpen { ifname, source }
+        }
+    }
+
+    // A missing "eth0.100" is likely a VLAN sub-interface that wasn't created
+    // yet if "eth0" exists, the hint says how
+    fn interface_not_found(ifname: &str, interfaces: &[NetworkInterface]) -> Self {
+        if let Some((parent, vid)) = ifname.rsplit_once('.') {
+            let vid = vid.parse().ok().filter(|vid| (1..=4094).contains(vid));
+            if let Some(vid) = vid.filter(|_| interfaces.iter().any(|i| i.name == parent)) {
+                return Error::VlanNotFound {
+                    ifname: ifname.to_string(),
+                    parent: parent.to_string(),
+                    vid,
+                };
+            }
+        }
+        Error::InterfaceNotFound(ifname.to_string())
+    }
+
+    // EX_NOPERM from sysexits.h for privilege problems, 1 for the rest
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::PermissionDenied { .. } => 77,
+            _ => 1,
+        }
+    }
+
+    fn exit(&self) -> ! {
+        clear_progress();
+        eprintln!("{}", self);
+        process::exit(self.exit_code());
+    }
+}
+
+fn exe_path() -> String {
+    std::env::current_exe()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "l2perf".to_string())
+}
+
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "l2perf")]
 struct Opt {
-    #[structopt(short, long, default_value = "1.0", help = "Bandwidth in Mbits/s")]
+    #[structopt(
+        short,
+        long,
+        default_value = "1.0",
+        parse(try_from_str = parse_bandwidth),
+        help = "Bandwidth in Mbits/s, or with a k/M/G suffix"
+    )]
     bandwidth: f32,
     #[structopt(short, long, default_value = "10", help = "Duration in seconds")]
     tsecs: u64,
-    #[structopt(short, long, default_value = "7380", parse(try_from_str = parse_hex), help = "Ethertype in hex")]
+    #[structopt(short, long, default_value = "7380", parse(try_from_str = parse_ethertype), help = "Ethertype in hex or a name like ipv4, arp, vlan, lldp")]
     ethertype: u16,
     #[structopt(short, long, default_value = "1500", help = "Payload size")]
     psize: usize,
@@ -33,273 +179,8516 @@ struct Opt {
     ifname: String,
     #[structopt(
         name = "DEST",
-        required_unless("rx"),
+        required_unless_one(&["rx", "rx-ifname", "import", "dst-self", "selftest", "sniff", "preset"]),
         help = "Destination MAC addr for TX mode"
     )]
     dest: Option<MacAddr>,
     #[structopt(short, long, help = "RX mode")]
     rx: bool,
+    #[structopt(
+        long,
+        requires("rx-ifname"),
+        conflicts_with("rx"),
+        help = "Loopback mode: TX interface"
+    )]
+    tx_ifname: Option<String>,
+    #[structopt(
+        long,
+        requires("tx-ifname"),
+        help = "Loopback mode: RX interface, DEST defaults to its MAC"
+    )]
+    rx_ifname: Option<String>,
+    #[structopt(long, help = "Print distribution of interval drop rates in RX summary")]
+    interval_histogram: bool,
+    #[structopt(
+        long,
+        help = "RX: also report the rate without duplicate frames in the summary"
+    )]
+    unique_rate: bool,
+    #[structopt(
+        long,
+        help = "RX: count frames whose payload is identical to a recent one, copies made by mirroring or loops"
+    )]
+    payload_dups: bool,
+    #[structopt(
+        long,
+        help = "RX: report how long after the sender stopped the last frames arrived, needs synchronized clocks across hosts"
+    )]
+    drain_time: bool,
+    #[structopt(
+        long,
+        help = "RX: print hints on the likely misconfiguration if no test frame arrived this many seconds after starting"
+    )]
+    startup_timeout: Option<u64>,
+    #[structopt(
+        long,
+        requires = "startup-timeout",
+        help = "RX: exit with status 1 after the --startup-timeout hints instead of waiting on"
+    )]
+    startup_fail: bool,
+    #[structopt(
+        long,
+        requires = "control",
+        conflicts_with_all(&["ipg-ns", "max-pps"]),
+        help = "TX: adjust the rate each interval (AIMD) to hold this drop percentage at the receiver, which reports over --control"
+    )]
+    target_drop: Option<f32>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_bandwidth),
+        requires = "target-drop",
+        help = "TX: rate added per interval under the --target-drop, same format as --bandwidth [default: 5% of --bandwidth]"
+    )]
+    aimd_step: Option<f32>,
+    #[structopt(
+        long,
+        default_value = "0.1",
+        help = "TX: fraction of the rate taken off per interval over the --target-drop"
+    )]
+    aimd_backoff: f32,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_bandwidth),
+        requires = "target-drop",
+        help = "TX: lowest rate --target-drop goes down to, --bandwidth is the highest [default: 1% of --bandwidth]"
+    )]
+    min_bandwidth: Option<f32>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_preset),
+        conflicts_with_all(&[
+            "rx", "tx-ifname", "handshake", "control", "payload-prefix", "counter-payload",
+            "entropy", "ethertype-sweep", "size-sweep", "train", "timestamp-every", "bufferbloat",
+        ]),
+        help = "TX: send protocol frames instead of test frames: pause-frame, lldp or arp-storm. Sets the ethertype, size and, without DEST, the destination"
+    )]
+    preset: Option<Preset>,
+    #[structopt(
+        long,
+        help = "RX: frames up to N behind the highest sequence number count as reordered, later ones as lost"
+    )]
+    reorder_window: Option<u64>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_bandwidth),
+        help = "RX: measure frame delay variation against a constant-rate sender at this bandwidth, no clock sync needed"
+    )]
+    fdv_rate: Option<f32>,
+    #[structopt(
+        long,
+        help = "RX: report the Allan deviation of the inter-arrival times over 1 to 10000 frames"
+    )]
+    allan: bool,
+    #[structopt(long, default_value = "0", help = "Initial sequence number")]
+    start_seq: Seq,
+    #[structopt(
+        long,
+        help = "Show interval reports as a status line on stderr, keeping stdout for summaries"
+    )]
+    progress: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_hex_bytes),
+        help = "Bytes in hex placed at the start of the payload, after the Id header"
+    )]
+    payload_prefix: Option<HexBytes>,
+    #[structopt(
+        long,
+        requires("payload-prefix"),
+        help = "Place the payload prefix before the Id header (RX needs the same prefix options)"
+    )]
+    prefix_before_id: bool,
+    #[structopt(
+        long,
+        help = "Send as fast as possible ignoring --bandwidth, reports peak/average PPS"
+    )]
+    max_pps: bool,
+    #[structopt(
+        long,
+        default_value = "1",
+        parse(try_from_str = parse_sample),
+        help = "RX: decode only every Nth frame (N or 1/N), counting the rest for rate and drops"
+    )]
+    sample: u64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Seconds to wait for a downed link to come back before finishing the test"
+    )]
+    link_grace: u64,
+    #[structopt(
+        long,
+        help = "Wait up to this many seconds for the link to come up before starting, Linux only"
+    )]
+    wait_for_link: Option<u64>,
+    #[structopt(
+        long,
+        help = "Stop any mode after this many seconds of wall-clock time, printing the summaries so far"
+    )]
+    deadline: Option<u64>,
+    #[structopt(long, help = "Print reports as JSON lines")]
+    json: bool,
+    #[structopt(
+        long,
+        conflicts_with_all(&["json", "oneline", "progress"]),
+        help = "Print a single JSON document in iperf3's result schema at the end instead of the reports"
+    )]
+    iperf3_json: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_columns),
+        help = "Columns of the text reports, in order: time, pkts, drops, rate, pps, jitter, latency"
+    )]
+    columns: Option<Columns>,
+    #[structopt(long, help = "TX: socket priority (SO_PRIORITY), Linux only")]
+    tx_priority: Option<u32>,
+    #[structopt(
+        long,
+        help = "TX: send on this hardware queue by bypassing the qdisc and pinning to the same CPU, Linux only"
+    )]
+    tx_queue: Option<usize>,
+    #[structopt(
+        long,
+        help = "TX: run the TX thread with real-time scheduling (SCHED_FIFO) at this priority (1-99), needs CAP_SYS_NICE, Linux only"
+    )]
+    rt_priority: Option<u8>,
+    #[structopt(
+        long,
+        default_value = "mbps",
+        parse(try_from_str = parse_units),
+        help = "Rate units: bps, kbps, mbps, gbps or auto"
+    )]
+    units: Units,
+    #[structopt(
+        long,
+        help = "Decimals of every fractional value in the text reports, instead of the usual 0 to 3"
+    )]
+    precision: Option<usize>,
+    #[structopt(
+        long,
+        conflicts_with = "precision",
+        help = "Print the text reports' values at full precision, rates in bps and the byte counts"
+    )]
+    raw_numbers: bool,
+    #[structopt(
+        long,
+        help = "Tag every report with this label, in text, JSON and CSV output"
+    )]
+    label: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with("sample"),
+        help = "RX: list the lost sequence number ranges in the summary"
+    )]
+    log_drops: bool,
+    #[structopt(
+        long,
+        help = "RX: capture whole frames and parse the Ethernet/VLAN header in l2perf"
+    )]
+    raw_capture: bool,
+    #[structopt(
+        long,
+        default_value = "1.0",
+        help = "TX: max seconds between frames at low rates, keep below the receiver's 2s timeout (0 disables)"
+    )]
+    keepalive: f32,
+    #[structopt(
+        long,
+        default_value = "0.0",
+        parse(try_from_str = parse_entropy),
+        help = "TX: share of random payload bytes after the header, 0.0 (zeros) to 1.0 (all random)"
+    )]
+    entropy: f64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "TX: seconds of low-rate probing before the --tsecs sustained phase, summarized separately"
+    )]
+    probe_secs: u64,
+    #[structopt(
+        long,
+        default_value = "1.0",
+        parse(try_from_str = parse_bandwidth),
+        help = "TX: bandwidth of the probe phase, same format as --bandwidth"
+    )]
+    probe_bandwidth: f32,
+    #[structopt(
+        long,
+        help = "RX: also print a cumulative summary of each stream every N seconds"
+    )]
+    rolling_summary: Option<u64>,
+    #[structopt(
+        long,
+        requires("tx-ifname"),
+        conflicts_with("DEST"),
+        help = "Loopback mode: send in both directions and compare the drop rates"
+    )]
+    bidir: bool,
+    #[structopt(
+        long,
+        default_value = "1.0",
+        help = "Difference in drop percentage points at which --bidir reports asymmetric loss"
+    )]
+    asymmetry_threshold: f32,
+    #[structopt(
+        long,
+        requires("bidir"),
+        help = "Payload size of the reverse direction of --bidir, defaults to --psize"
+    )]
+    reverse_psize: Option<usize>,
+    #[structopt(
+        long,
+        requires("bidir"),
+        parse(try_from_str = parse_bandwidth),
+        help = "Bandwidth of the reverse direction of --bidir, defaults to --bandwidth"
+    )]
+    reverse_bandwidth: Option<f32>,
+    #[structopt(
+        long,
+        requires("tx-ifname"),
+        help = "Loopback mode: hold every frame back this many ms before sending it, to check the latency stats"
+    )]
+    inject_delay: Option<f64>,
+    #[structopt(
+        long,
+        help = "RX: pin to this CPU and steer the interface's IRQs to it, Linux only"
+    )]
+    affinity_irq: Option<usize>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Also write all interval and summary reports to this file in a compact binary format"
+    )]
+    export: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Write the interval rates (and drops on RX) to this file as CSV, for plotting"
+    )]
+    plot_data: Option<PathBuf>,
+    #[structopt(
+        long,
+        requires("plot-data"),
+        help = "Also write a gnuplot script for the --plot-data file next to it, FILE.gp"
+    )]
+    plot_script: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all(&["rx", "tx-ifname", "export"]),
+        help = "Print the summaries of a file written by --export and exit"
+    )]
+    import: Option<PathBuf>,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Run the test N times and print the mean and standard deviation across runs"
+    )]
+    repeat: u32,
+    #[structopt(
+        long,
+        conflicts_with_all(&["bandwidth", "max-pps"]),
+        help = "TX: fixed gap between frames in nanoseconds instead of --bandwidth pacing"
+    )]
+    ipg_ns: Option<u64>,
+    #[structopt(
+        long,
+        conflicts_with("sample"),
+        help = "RX: exit with an error at the first lost, duplicate or reordered frame"
+    )]
+    strict_sequence: bool,
+    #[structopt(
+        long,
+        help = "Show the time from start until the channel was ready and the first frame in summaries"
+    )]
+    setup_times: bool,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "RX: spread frames over N threads pinned to CPUs 0..N by the receiving CPU (RSS/RPS), Linux only"
+    )]
+    rx_threads: usize,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "RX: read up to N frames per system call (recvmmsg), Linux only"
+    )]
+    rx_batch: usize,
+    #[structopt(skip)]
+    rx_thread: Option<usize>, // Index of this thread with --rx-threads
+    #[structopt(
+        long,
+        conflicts_with("json"),
+        help = "Print summaries as a single line of key=value pairs"
+    )]
+    oneline: bool,
+    #[structopt(
+        long,
+        help = "Byte offset of the Id header in the payload (RX needs the same option)"
+    )]
+    id_offset: Option<usize>,
+    #[structopt(
+        long,
+        help = "RX: time the per-frame processing of every 64th frame and report the receiver load"
+    )]
+    rx_load: bool,
+    #[structopt(
+        long,
+        conflicts_with("DEST"),
+        help = "TX: send to the interface's own MAC, for hairpin tests through a switch"
+    )]
+    dst_self: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "TX: timestamp every Nth frame for RX latency stats, needs synced clocks across hosts (0 disables)"
+    )]
+    timestamp_every: u64,
+    #[structopt(
+        long,
+        help = "TX: send this fraction (0-1) of frames with a corrupted FCS, needs driver SO_NOFCS support, Linux only"
+    )]
+    bad_fcs: Option<f64>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_parallel),
+        conflicts_with_all(&["rx", "tx-ifname", "tx-queue", "ipg-ns"]),
+        help = "TX: send N streams, each pinned to a CPU with an equal share of the bandwidth, or \"auto\" for one per CPU"
+    )]
+    parallel: Option<Parallel>,
+    #[structopt(skip)]
+    tx_stream: Option<usize>, // Index of this stream with --parallel
+    #[structopt(
+        long,
+        requires("parallel"),
+        parse(try_from_str = parse_stagger),
+        help = "TX: delay the start of each --parallel stream by a random time up to this many ms, or \"auto\""
+    )]
+    stagger: Option<Duration>,
+    #[structopt(skip)]
+    start_delay: Duration, // Of this stream, drawn from --stagger
+    #[structopt(
+        long,
+        help = "Print the resolved options and derived values (MACs, frame size) before starting"
+    )]
+    show_config: bool,
+    #[structopt(
+        long,
+        conflicts_with_all(&["rx", "tx-ifname"]),
+        help = "TX: exchange a capabilities frame with the receiver first and stop if it lacks a needed feature"
+    )]
+    handshake: bool,
+    #[structopt(
+        long,
+        conflicts_with_all(&["rx", "timestamp-every"]),
+        help = "TX: bufferbloat test, timestamp a frame every 10ms through an idle --probe-secs phase (default 5) and the loaded phase"
+    )]
+    bufferbloat: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "TX: keep sending through up to N failed sends, logging each, and abort on the next one"
+    )]
+    max_errors: u64,
+    #[structopt(
+        long,
+        help = "Keep per-frame samples of a stream up to N, then only totals (no interval reports) to bound memory"
+    )]
+    max_samples: Option<usize>,
+    #[structopt(
+        long,
+        help = "RX: serve OpenMetrics (Prometheus) counters and the latency histogram over HTTP on this port"
+    )]
+    metrics_port: Option<u16>,
+    #[structopt(
+        long,
+        conflicts_with = "tx-ifname",
+        help = "Out-of-band UDP control channel at ip:port: RX listens there, TX asks the receiver there before starting and fetches its summary at the end"
+    )]
+    control: Option<SocketAddr>,
+    #[structopt(
+        long,
+        requires("metrics-port"),
+        help = "Attach the latest latency sample (stream, sequence) to each histogram bucket as an exemplar"
+    )]
+    metrics_exemplars: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "RX: append each stream summary as a CSV row, tagged with host and interface, to this shared file"
+    )]
+    receive_only_stats: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "RX: also write each stream summary, and a CSV row of it, to its own files in this directory"
+    )]
+    output_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Feed the RX statistics a generated stream with injected loss, duplicates and reordering, and check the results"
+    )]
+    selftest: bool,
+    #[structopt(
+        long,
+        help = "Diagnostic: count the frames on --ifname by ethertype for a few seconds, whatever --ethertype says, and exit"
+    )]
+    sniff: bool,
+    #[structopt(
+        long,
+        requires("rx"),
+        help = "RX: count the frames of the ethertype without reading an Id, for senders other than l2perf; rates only"
+    )]
+    raw_count: bool,
+    #[structopt(
+        long,
+        default_value = "0.01,0.01,0.01",
+        parse(try_from_str = parse_selftest_rates),
+        help = "Injected loss, duplicate and reorder rates for --selftest"
+    )]
+    selftest_rates: SelftestRates,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Random seed for --selftest and --stagger"
+    )]
+    seed: u64,
+    #[structopt(
+        long,
+        help = "fsync stdout (if a file), the --export file and --receive-only-stats after each report"
+    )]
+    durable: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_size_sweep),
+        help = "TX: run for --tsecs at each payload size MIN:MAX:STEP and print a table of rate and PPS"
+    )]
+    size_sweep: Option<SizeSweep>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all(&[
+            "rx", "ipg-ns", "max-pps", "target-drop", "preset", "size-sweep", "parallel", "bidir",
+        ]),
+        help = "TX: send frames at the offsets (seconds from the start) listed in this file, one per line with an optional payload size"
+    )]
+    schedule: Option<PathBuf>,
+    #[structopt(
+        long,
+        requires("schedule"),
+        help = "TX: start the --schedule over when it ends before --tsecs, instead of stopping"
+    )]
+    schedule_loop: bool,
+    #[structopt(
+        long,
+        help = "Fill the payload with its byte offset (0, 1, .. 255, 0, ..), RX checks it with the same option"
+    )]
+    counter_payload: bool,
+    #[structopt(
+        long,
+        conflicts_with("preset"),
+        help = "Carry each frame's payload length after the Id header, RX counts frames that arrive shorter with the same option"
+    )]
+    length_check: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_ethertype_sweep),
+        help = "Rotate the ethertype per frame over START:END, RX counts frames per ethertype with the same option"
+    )]
+    ethertype_sweep: Option<EthertypeSweep>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_ethertypes),
+        conflicts_with_all(&["ethertype-sweep", "preset"]),
+        help = "TX: one ethertype per --parallel stream, comma separated; RX accepts all of them with the same option"
+    )]
+    stream_ethertypes: Option<Ethertypes>,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "RX: merge intervals with fewer frames into the next one, their drop rate means little"
+    )]
+    min_interval_pkts: u64,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Also send interval and summary reports as JSON lines to this Unix socket, Unix only"
+    )]
+    result_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_bandwidth),
+        help = "Link speed in Mbits/s (or with a k/M/G suffix), summaries show the utilization"
+    )]
+    link_speed: Option<f32>,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "TX: send N unmeasured frames first to prime MAC learning and caches, receivers ignore them"
+    )]
+    train: u64,
+    #[structopt(
+        long,
+        help = "Loopback: before the test, check that the path carries the payload size and find its MTU if not"
+    )]
+    mtu_probe: bool,
+}
+
+fn parse_hex(src: &str) -> Result<u16, ParseIntError> {
+    u16::from_str_radix(src, 16)
+}
+
+// Well-known ethertypes accepted by name
+const ETHERTYPE_NAMES: &[(&str, u16)] = &[
+    ("ipv4", 0x0800),
+    ("arp", 0x0806),
+    ("vlan", ETHERTYPE_VLAN),
+    ("ipv6", 0x86dd),
+    ("mpls", 0x8847),
+    ("pppoe", 0x8864),
+    ("qinq", ETHERTYPE_QINQ),
+    ("lldp", 0x88cc),
+    ("ptp", 0x88f7),
+];
+
+fn parse_ethertype(src: &str) -> Result<u16, String> {
+    let name = src.to_ascii_lowercase();
+    if let Some(&(_, ethertype)) = ETHERTYPE_NAMES.iter().find(|(n, _)| *n == name) {
+        return Ok(ethertype);
+    }
+    parse_hex(src).map_err(|e| format!("not a known ethertype name or hex value: {}", e))
+}
+
+// Name suffix for messages, empty for unnamed (e.g. experimental) ethertypes
+fn ethertype_label(ethertype: u16) -> String {
+    ETHERTYPE_NAMES
+        .iter()
+        .find(|(_, e)| *e == ethertype)
+        .map_or(String::new(), |(n, _)| format!(" ({})", n))
+}
+
+// --ethertype-sweep range, inclusive. Frame `seq` carries ethertype
+// `start + seq % len`, so the receiver knows which frames each one should get.
+#[derive(Debug, Clone, Copy)]
+struct EthertypeSweep {
+    start: u16,
+    end: u16,
+}
+
+impl EthertypeSweep {
+    fn len(self) -> u64 {
+        u64::from(self.end - self.start) + 1
+    }
+
+    fn nth(self, seq: Seq) -> u16 {
+        self.start + (seq % self.len()) as u16
+    }
+
+    fn contains(self, ethertype: u16) -> bool {
+        (self.start..=self.end).contains(&ethertype)
+    }
+}
+
+// Ranges with up to this many ethertypes list each one in the text summary
+const MAX_ETHERTYPE_LINES: u64 = 32;
+
+fn parse_ethertype_sweep(src: &str) -> Result<EthertypeSweep, String> {
+    let (start, end) = src.split_once(':').ok_or("expected START:END")?;
+    let sweep = EthertypeSweep {
+        start: parse_ethertype(start)?,
+        end: parse_ethertype(end)?,
+    };
+    // Smaller values are 802.3 length fields, not ethertypes
+    if sweep.start < 0x0600 {
+        return Err("ethertypes start at 0x0600".to_string());
+    }
+    if sweep.start > sweep.end {
+        return Err("START is larger than END".to_string());
+    }
+    Ok(sweep)
+}
+
+// Ethertypes of the --parallel streams, in stream order
+#[derive(Debug, Clone)]
+struct Ethertypes(Vec<u16>);
+
+fn parse_ethertypes(src: &str) -> Result<Ethertypes, String> {
+    let ethertypes = src
+        .split(',')
+        .map(|s| parse_ethertype(s.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(&short) = ethertypes.iter().find(|&&e| e < 0x0600) {
+        return Err(format!(
+            "{:x} is an 802.3 length, ethertypes start at 0x0600",
+            short
+        ));
+    }
+    Ok(Ethertypes(ethertypes))
+}
+
+fn parse_entropy(src: &str) -> Result<f64, String> {
+    let entropy: f64 = src.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&entropy) {
+        return Err("entropy must be between 0.0 and 1.0".to_string());
+    }
+    Ok(entropy)
+}
+
+// Per-frame probabilities of the faults --selftest injects
+#[derive(Debug, Clone, Copy)]
+struct SelftestRates {
+    loss: f64,
+    dup: f64,
+    reorder: f64,
+}
+
+// Accepts "LOSS,DUP,REORDER", each between 0.0 and 1.0
+fn parse_selftest_rates(src: &str) -> Result<SelftestRates, String> {
+    let rates = src
+        .split(',')
+        .map(parse_entropy)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "rates must be between 0.0 and 1.0".to_string())?;
+    match rates[..] {
+        [loss, dup, reorder] => Ok(SelftestRates { loss, dup, reorder }),
+        _ => Err("expected three rates: loss,duplicate,reorder".to_string()),
+    }
+}
+
+// Mbits/s from a bare number (already Mbits/s) or one with a k, M or G suffix
+fn parse_bandwidth(src: &str) -> Result<f32, String> {
+    let (num, scale) = match src.chars().last() {
+        Some('k') | Some('K') => (&src[..src.len() - 1], 0.001),
+        Some('m') | Some('M') => (&src[..src.len() - 1], 1.0),
+        Some('g') | Some('G') => (&src[..src.len() - 1], 1000.0),
+        _ => (src, 1.0),
+    };
+    let value: f32 = num.parse().map_err(|e| format!("{}", e))?;
+    if !(value > 0.0 && value.is_finite()) {
+        return Err("bandwidth must be positive".to_string());
+    }
+    Ok(value * scale)
+}
+
+// Stream count for --parallel
+#[derive(Debug, Clone, Copy)]
+enum Parallel {
+    Auto,
+    Count(usize),
+}
+
+// Upper bound for --parallel auto, past this more senders mostly contend for
+// the same NIC queues
+const MAX_PARALLEL: usize = 16;
+
+fn parse_parallel(src: &str) -> Result<Parallel, String> {
+    if src == "auto" {
+        return Ok(Parallel::Auto);
+    }
+    match src.parse() {
+        Ok(0) => Err("stream count must be at least 1".to_string()),
+        Ok(n) => Ok(Parallel::Count(n)),
+        Err(e) => Err(format!("{}, expected a stream count or \"auto\"", e)),
+    }
+}
+
+// Upper bound of --stagger auto: one pacing step of tx_traffic, the period at
+// which rate-limited streams send their bursts
+const STAGGER_AUTO: Duration = Duration::from_millis(10);
+
+fn parse_stagger(src: &str) -> Result<Duration, String> {
+    if src == "auto" {
+        return Ok(STAGGER_AUTO);
+    }
+    let ms: f64 = src
+        .parse()
+        .map_err(|e| format!("{}, expected milliseconds or \"auto\"", e))?;
+    if !(ms >= 0.0 && ms.is_finite()) {
+        return Err("stagger can't be negative".to_string());
+    }
+    Ok(Duration::from_secs_f64(ms / 1e3))
+}
+
+// Payload sizes of a --size-sweep, inclusive
+#[derive(Debug, Clone, Copy)]
+struct SizeSweep {
+    min: usize,
+    max: usize,
+    step: usize,
+}
+
+impl SizeSweep {
+    fn sizes(self) -> impl Iterator<Item = usize> {
+        (self.min..=self.max).step_by(self.step)
+    }
+}
+
+fn parse_size_sweep(src: &str) -> Result<SizeSweep, String> {
+    let parts: Vec<&str> = src.split(':').collect();
+    let [min, max, step] = parts[..] else {
+        return Err("expected MIN:MAX:STEP".to_string());
+    };
+    let num = |s: &str| s.parse::<usize>().map_err(|e| format!("{}: {}", s, e));
+    let sweep = SizeSweep {
+        min: num(min)?,
+        max: num(max)?,
+        step: num(step)?,
+    };
+    if sweep.step == 0 {
+        return Err("step must be at least 1".to_string());
+    }
+    if sweep.min > sweep.max {
+        return Err("MIN is larger than MAX".to_string());
+    }
+    Ok(sweep)
+}
+
+// Accepts "N" or "1/N"
+fn parse_sample(src: &str) -> Result<u64, String> {
+    let n: u64 = src
+        .strip_prefix("1/")
+        .unwrap_or(src)
+        .parse()
+        .map_err(|e| format!("{}", e))?;
+    if n == 0 {
+        return Err("sampling rate must be at least 1".to_string());
+    }
+    Ok(n)
+}
+
+#[derive(Debug, Clone)]
+struct HexBytes(Vec<u8>);
+
+fn parse_hex_bytes(src: &str) -> Result<HexBytes, String> {
+    let digits = src.trim_start_matches("0x");
+    if digits.is_empty() || !digits.is_ascii() || !digits.len().is_multiple_of(2) {
+        return Err("expected an even number of hex digits".to_string());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect::<Result<_, _>>()
+        .map(HexBytes)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+enum Unit {
+    #[serde(rename = "bps")]
+    Bps,
+    Kbps,
+    Mbps,
+    Gbps,
+}
+
+// The unit field of JSON output describes its *_bps values, which are always
+// bits per second whatever --units picked for the text
+fn serialize_bps<S: serde::Serializer>(_: &Unit, s: S) -> Result<S::Ok, S::Error> {
+    Unit::Bps.serialize(s)
+}
+
+impl Unit {
+    fn divisor(self) -> f32 {
+        match self {
+            Unit::Bps => 1.0,
+            Unit::Kbps => 1_000.0,
+            Unit::Mbps => 1_000_000.0,
+            Unit::Gbps => 1_000_000_000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Units {
+    Fixed(Unit),
+    Auto,
+}
+
+impl Units {
+    fn pick(self, bps: f32) -> Unit {
+        match self {
+            Units::Fixed(unit) => unit,
+            Units::Auto if bps >= 1_000_000_000.0 => Unit::Gbps,
+            Units::Auto if bps >= 1_000_000.0 => Unit::Mbps,
+            Units::Auto => Unit::Kbps,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unit::Bps => write!(f, "bps"),
+            unit => write!(f, "{:?}", unit),
+        }
+    }
+}
+
+fn parse_units(src: &str) -> Result<Units, String> {
+    match src.to_lowercase().as_str() {
+        "bps" => Ok(Units::Fixed(Unit::Bps)),
+        "kbps" => Ok(Units::Fixed(Unit::Kbps)),
+        "mbps" => Ok(Units::Fixed(Unit::Mbps)),
+        "gbps" => Ok(Units::Fixed(Unit::Gbps)),
+        "auto" => Ok(Units::Auto),
+        _ => Err("expected one of bps, kbps, mbps, gbps, auto".to_string()),
+    }
+}
+
+// How the text reports print fractional values, set from --precision and
+// --raw-numbers. Unset, each value keeps the decimals it always had.
+#[derive(Debug, Clone, Copy)]
+enum Precision {
+    Places(usize),
+    Raw, // Every digit f32/f64 has
+}
+
+static PRECISION: OnceLock<Precision> = OnceLock::new();
+
+fn raw_numbers() -> bool {
+    matches!(PRECISION.get(), Some(Precision::Raw))
+}
+
+// A value of a text report and its default number of decimals. Width and `+`
+// still apply, e.g. `{:>12}` or `{:+}`.
+struct Num<T>(T, usize);
+
+impl<T: fmt::Display> fmt::Display for Num<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = match PRECISION.get() {
+            Some(Precision::Raw) => self.0.to_string(),
+            Some(Precision::Places(places)) => format!("{:.*}", places, self.0),
+            None => format!("{:.*}", self.1, self.0),
+        };
+        if f.sign_plus() && !s.starts_with('-') {
+            s.insert(0, '+');
+        }
+        f.pad(&s)
+    }
+}
+
+// Well-formed protocol frames sent at the test's pace by --preset, to see how
+// the DUT reacts. They carry no Id, so there's nothing for a receiver to count.
+#[derive(Debug, Clone, Copy)]
+enum Preset {
+    PauseFrame,
+    Lldp,
+    ArpStorm,
+}
+
+fn parse_preset(src: &str) -> Result<Preset, String> {
+    match src.to_lowercase().as_str() {
+        "pause-frame" => Ok(Preset::PauseFrame),
+        "lldp" => Ok(Preset::Lldp),
+        "arp-storm" => Ok(Preset::ArpStorm),
+        _ => Err("expected one of pause-frame, lldp, arp-storm".to_string()),
+    }
+}
+
+impl Preset {
+    fn ethertype(self) -> u16 {
+        match self {
+            Preset::PauseFrame => 0x8808,
+            Preset::Lldp => 0x88cc,
+            Preset::ArpStorm => 0x0806,
+        }
+    }
+
+    fn dest(self) -> MacAddr {
+        match self {
+            // Reserved for MAC control, never forwarded by a bridge
+            Preset::PauseFrame => MacAddr::new(0x01, 0x80, 0xc2, 0x00, 0x00, 0x01),
+            // Nearest bridge
+            Preset::Lldp => MacAddr::new(0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e),
+            Preset::ArpStorm => MacAddr::broadcast(),
+        }
+    }
+
+    // The payload after the Ethernet header, padded to the minimum frame size
+    fn payload(self, src: MacAddr) -> Vec<u8> {
+        let mut payload = match self {
+            // 802.3 Annex 31B PAUSE: opcode 0x0001, then the pause time in
+            // quanta of 512 bit times, here the longest
+            Preset::PauseFrame => vec![0x00, 0x01, 0xff, 0xff],
+            // 802.1AB LLDPDU: Chassis ID (MAC address subtype), Port ID
+            // (locally assigned subtype), TTL of 120s, End. Each TLV starts
+            // with 7 bits of type and 9 bits of length.
+            Preset::Lldp => {
+                let mut lldpdu = vec![];
+                let mut tlv = |kind: u16, value: &[u8]| {
+                    let head = kind << 9 | value.len() as u16;
+                    lldpdu.extend_from_slice(&head.to_be_bytes());
+                    lldpdu.extend_from_slice(value);
+                };
+                tlv(1, &[&[4][..], &src.octets()].concat());
+                tlv(2, b"\x07l2perf");
+                tlv(3, &120u16.to_be_bytes());
+                tlv(0, &[]);
+                lldpdu
+            }
+            // RFC 826 request from src for 192.0.2.1, asking as 192.0.2.2
+            // (RFC 5737 documentation addresses)
+            Preset::ArpStorm => {
+                let mut arp = vec![0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01];
+                arp.extend_from_slice(&src.octets());
+                arp.extend_from_slice(&[192, 0, 2, 2]);
+                arp.extend_from_slice(&[0; 6]);
+                arp.extend_from_slice(&[192, 0, 2, 1]);
+                arp
+            }
+        };
+        payload.resize(MIN_PAYLOAD as usize, 0);
+        payload
+    }
+}
+
+// Cross-field checks that structopt can't express
+fn check_opts(opts: &Opt) -> Result<(), Error> {
+    // A newline would split a report line, or a CSV row, in two
+    if opts
+        .label
+        .as_ref()
+        .is_some_and(|l| l.chars().any(char::is_control))
+    {
+        return Err(Error::Config(
+            "--label can't contain control characters".to_string(),
+        ));
+    }
+    if opts.oneline
+        && opts
+            .label
+            .as_ref()
+            .is_some_and(|l| l.chars().any(char::is_whitespace))
+    {
+        return Err(Error::Config(
+            "--label can't contain spaces with --oneline, which separates its fields with them"
+                .to_string(),
+        ));
+    }
+    let id_size = head_size(opts);
+    if opts.psize < id_size {
+        return Err(Error::Config(format!(
+            "Payload size {} is too small for the {} byte Id header",
+            opts.psize, id_size
+        )));
+    }
+    if opts.bad_fcs.is_some_and(|f| !(0.0..=1.0).contains(&f)) {
+        return Err(Error::Config(
+            "Bad FCS fraction must be between 0 and 1".to_string(),
+        ));
+    }
+    if opts.max_samples.is_some_and(|max| max < 2) {
+        return Err(Error::Config(
+            "--max-samples must be at least 2".to_string(),
+        ));
+    }
+    if !(opts.keepalive >= 0.0 && opts.keepalive.is_finite()) {
+        return Err(Error::Config(
+            "Keepalive interval can't be negative".to_string(),
+        ));
+    }
+    if opts.rx_threads == 0 {
+        return Err(Error::Config(
+            "RX thread count must be at least 1".to_string(),
+        ));
+    }
+    if opts.mtu_probe && (opts.tx_ifname.is_none() || opts.rx_ifname.is_none()) {
+        return Err(Error::Config(
+            "--mtu-probe needs both ends, --tx-ifname and --rx-ifname".to_string(),
+        ));
+    }
+    if opts.rt_priority.is_some_and(|p| !(1..=99).contains(&p)) {
+        return Err(Error::Config(
+            "--rt-priority must be between 1 and 99".to_string(),
+        ));
+    }
+    if opts.schedule.is_some() && opts.probe_secs > 0 {
+        return Err(Error::Config(
+            "--schedule sets every send time, it can't be combined with a probe phase".to_string(),
+        ));
+    }
+    if let Some(ethertypes) = &opts.stream_ethertypes {
+        match opts.parallel {
+            None if !opts.rx => {
+                return Err(Error::Config(
+                    "--stream-ethertypes needs --parallel on TX".to_string(),
+                ))
+            }
+            // Checked once auto has picked the count
+            Some(Parallel::Count(n)) if ethertypes.0.len() != n => {
+                return Err(Error::Config(format!(
+                    "--stream-ethertypes lists {} ethertypes for {} streams",
+                    ethertypes.0.len(),
+                    n
+                )))
+            }
+            _ => {}
+        }
+    }
+    if opts
+        .inject_delay
+        .is_some_and(|ms| !(ms >= 0.0 && ms.is_finite()))
+    {
+        return Err(Error::Config(
+            "Injected delay can't be negative".to_string(),
+        ));
+    }
+    if opts
+        .target_drop
+        .is_some_and(|pct| !(0.0..100.0).contains(&pct))
+    {
+        return Err(Error::Config(
+            "Target drop must be at least 0 and below 100 percent".to_string(),
+        ));
+    }
+    if !(opts.aimd_backoff > 0.0 && opts.aimd_backoff < 1.0) {
+        return Err(Error::Config(
+            "AIMD backoff must be between 0 and 1".to_string(),
+        ));
+    }
+    if opts.min_bandwidth.is_some_and(|min| min > opts.bandwidth) {
+        return Err(Error::Config(
+            "Minimum bandwidth is above --bandwidth".to_string(),
+        ));
+    }
+    if opts.deadline == Some(0) {
+        return Err(Error::Config("Deadline must be at least 1s".to_string()));
+    }
+    if opts.rx_batch == 0 {
+        return Err(Error::Config(
+            "RX batch size must be at least 1".to_string(),
+        ));
+    }
+    if opts.rx_threads > 1 && !opts.rx {
+        return Err(Error::Config("--rx-threads needs --rx".to_string()));
+    }
+    if opts.repeat == 0 {
+        return Err(Error::Config("Repeat count must be at least 1".to_string()));
+    }
+    // Not a clap conflict, that would fire on the default value too
+    if opts.repeat > 1 && (opts.rx || opts.bidir || opts.import.is_some()) {
+        return Err(Error::Config(
+            "--repeat can't be used with --rx, --bidir or --import".to_string(),
+        ));
+    }
+    if opts.size_sweep.is_some() && (opts.rx || opts.bidir || opts.repeat > 1) {
+        return Err(Error::Config(
+            "--size-sweep can't be used with --rx, --bidir or --repeat".to_string(),
+        ));
+    }
+    if opts.counter_payload && opts.entropy > 0.0 {
+        return Err(Error::Config(
+            "--counter-payload can't be used with --entropy".to_string(),
+        ));
+    }
+    if cfg!(not(unix)) && opts.result_socket.is_some() {
+        return Err(Error::Config(
+            "--result-socket needs Unix domain sockets".to_string(),
+        ));
+    }
+    if opts.rolling_summary == Some(0) {
+        return Err(Error::Config(
+            "Rolling summary interval must be at least 1s".to_string(),
+        ));
+    }
+    let prefix_len = opts.payload_prefix.as_ref().map_or(0, |p| p.0.len());
+    if prefix_len + id_size > opts.psize {
+        return Err(Error::Config(format!(
+            "Payload prefix ({} bytes) and Id header ({} bytes) don't fit in payload size {}",
+            prefix_len, id_size, opts.psize
+        )));
+    }
+    let id_off = id_offset(opts);
+    if id_off + id_size > opts.psize {
+        return Err(Error::Config(format!(
+            "Id header at offset {} doesn't fit in payload size {}",
+            id_off, opts.psize
+        )));
+    }
+    if prefix_offset(opts) + prefix_len > opts.psize {
+        return Err(Error::Config(format!(
+            "Payload prefix ({} bytes) after the Id header at offset {} doesn't fit in payload size {}",
+            prefix_len, id_off, opts.psize
+        )));
+    }
+    if opts.prefix_before_id && prefix_len > id_off {
+        return Err(Error::Config(format!(
+            "Payload prefix ({} bytes) overlaps the Id header at offset {}",
+            prefix_len, id_off
+        )));
+    }
+    // The reverse direction of --bidir has to pass the same checks
+    if opts.bidir && opts.reverse_psize.is_some() {
+        if let Err(Error::Config(e)) = check_opts(&reverse_opts(opts)) {
+            return Err(Error::Config(format!("Reverse direction: {}", e)));
+        }
+    }
+    Ok(())
+}
+
+// Options of the reverse direction of --bidir
+fn reverse_opts(opts: &Opt) -> Opt {
+    let mut reverse = opts.clone();
+    reverse.psize = opts.reverse_psize.unwrap_or(opts.psize);
+    reverse.bandwidth = opts.reverse_bandwidth.unwrap_or(opts.bandwidth);
+    reverse.reverse_psize = None;
+    reverse.reverse_bandwidth = None;
+    reverse
+}
+
+// What a received frame carries
+#[derive(Debug)]
+enum Body {
+    Id(Id),
+    Hello(Hello),
+    Skipped,  // Not decoded, see --sample
+    Training, // Not measured, see --train
+}
+
+// A received frame with its headers stripped, see parse_frame
+#[derive(Debug)]
+struct ParsedFrame {
+    body: Body,
+    len: usize,                // Payload bytes
+    vlan: Option<u16>,         // Outer VLAN id, raw capture only
+    src: Option<MacAddr>,      // Raw capture only
+    ethertype: Option<u16>,    // Raw capture only
+    corrupt_at: Option<usize>, // First payload byte off the --counter-payload pattern
+    hash: Option<u64>,         // Of the payload, see --payload-dups
+    sent_len: Option<usize>,   // Payload bytes as sent, see --length-check
+}
+
+// Strip the headers of a received frame and decode its payload. Layer3
+// capture delivers just the payload, raw capture the whole frame, which may
+// carry 802.1Q/802.1ad tags and other ethertypes. Unless `decode` is set
+// (frames skipped by --sample) only a sentinel, spotted by its `last` byte, or
+// a Hello is decoded.
+fn parse_frame(raw: &[u8], opts: &Opt, decode: bool) -> Result<ParsedFrame, Error> {
+    let mut off = 0;
+    let mut vlan = None;
+    let mut src = None;
+    let mut ethertype = None;
+    if opts.raw_capture {
+        let field = |off: usize| {
+            raw.get(off..off + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or(Error::MalformedFrame(raw.len()))
+        };
+        src = raw
+            .get(6..12)
+            .map(|b| MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5]));
+        off = 12; // DST + SRC
+        loop {
+            let typ = field(off)?;
+            off += 2;
+            match typ {
+                ETHERTYPE_VLAN | ETHERTYPE_QINQ => {
+                    vlan = vlan.or(Some(field(off)? & 0xfff));
+                    off += 2; // Skip the TCI
+                }
+                t if t == opts.ethertype
+                    || opts.ethertype_sweep.is_some_and(|s| s.contains(t))
+                    || opts
+                        .stream_ethertypes
+                        .as_ref()
+                        .is_some_and(|l| l.0.contains(&t)) =>
+                {
+                    ethertype = Some(t);
+                    break;
+                }
+                t => return Err(Error::WrongEthertype(t)),
+            }
+        }
+    }
+
+    let payload = &raw[off..];
+    let id_off = id_offset(opts);
+    let body = if payload.starts_with(TRAIN_MAGIC) {
+        Body::Training
+    } else if let Some(hello) = Hello::parse(payload) {
+        Body::Hello(hello)
+    } else if !decode && payload.get(id_off + ID_LAST_OFFSET) != Some(&1) {
+        Body::Skipped
+    } else {
+        Body::Id(Id::parse(payload, id_off)?)
+    };
+    let corrupt_at = match body {
+        Body::Id(_) if opts.counter_payload => counter_mismatch(payload, opts),
+        _ => None,
+    };
+    let hash = match body {
+        Body::Id(_) if opts.payload_dups => {
+            let mut hasher = DefaultHasher::new();
+            payload.hash(&mut hasher);
+            Some(hasher.finish())
+        }
+        _ => None,
+    };
+    let sent_len = match body {
+        Body::Id(_) if opts.length_check => {
+            let off = id_off + Id::size();
+            payload
+                .get(off..off + LENGTH_SIZE)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        }
+        _ => None,
+    };
+    Ok(ParsedFrame {
+        body,
+        len: payload.len(),
+        vlan,
+        src,
+        ethertype,
+        corrupt_at,
+        hash,
+        sent_len,
+    })
+}
+
+// --counter-payload: every byte holds its payload offset modulo 256, except
+// where the Id header and the payload prefix are written over it. Returns the
+// first offset that doesn't.
+fn counter_mismatch(payload: &[u8], opts: &Opt) -> Option<usize> {
+    let id = id_offset(opts)..id_offset(opts) + head_size(opts);
+    let prefix_len = opts.payload_prefix.as_ref().map_or(0, |p| p.0.len());
+    let prefix = prefix_offset(opts)..prefix_offset(opts) + prefix_len;
+    payload
+        .iter()
+        .enumerate()
+        .position(|(i, &b)| b != i as u8 && !id.contains(&i) && !prefix.contains(&i))
+}
+
+// Wall clock for frame timestamps, only comparable across hosts with synced
+// clocks (e.g. PTP)
+fn unix_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// Offset of the serialized Id within the payload
+fn id_offset(opts: &Opt) -> usize {
+    if let Some(off) = opts.id_offset {
+        return off;
+    }
+    match &opts.payload_prefix {
+        Some(prefix) if opts.prefix_before_id => prefix.0.len(),
+        _ => 0,
+    }
+}
+
+// With --length-check the Id is followed by the payload length (u32, little
+// endian), together they make up the header
+const LENGTH_SIZE: usize = 4;
+
+fn head_size(opts: &Opt) -> usize {
+    Id::size() + if opts.length_check { LENGTH_SIZE } else { 0 }
+}
+
+// Offset of the payload prefix, at the start or right after the Id
+fn prefix_offset(opts: &Opt) -> usize {
+    if opts.prefix_before_id {
+        0
+    } else {
+        id_offset(opts) + head_size(opts)
+    }
+}
+
+// Per-frame sequence number. It wraps around on overflow, so all comparisons
+// and differences go through the wrapping helpers below.
+type Seq = u64;
+
+// Number of sequence numbers from `first` to `last` inclusive, where `first`
+// doesn't come after `last` (e.g. the lowest and highest seen)
+fn seq_span(first: Seq, last: Seq) -> u64 {
+    last.wrapping_sub(first) + 1
+}
+
+// Serial number comparison (RFC 1982): true if `a` comes before `b`
+fn seq_before(a: Seq, b: Seq) -> bool {
+    (a.wrapping_sub(b) as i64) < 0
+}
+
+// Encoding of everything l2perf puts on the wire or in an --export file:
+// bincode with little-endian, fixed-size integers on every host, and trailing
+// bytes allowed as frames are padded. That's the layout of bincode's top-level
+// functions, spelled out as `DefaultOptions` alone would pick varints.
+fn wire() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+// On the wire (see `wire`), 23 bytes: id (4), cnt (8), last, probe and
+// stamped (1 each, 0 or 1) and ts_ns (8)
+#[derive(Debug, Serialize, Deserialize)]
+struct Id {
+    id: u32,
+    cnt: Seq,
+    last: bool,
+    probe: bool,   // Sent during the --probe-secs phase
+    stamped: bool, // `ts_ns` is set, see --timestamp-every
+    ts_ns: u64,    // Send time in nanoseconds since the Unix epoch
+}
+
+impl Id {
+    pub fn new(id: u32, start: Seq) -> Self {
+        Self {
+            id,
+            cnt: start,
+            last: false,
+            probe: false,
+            stamped: false,
+            ts_ns: 0,
+        }
+    }
+
+    // Serialized size of the header
+    fn size() -> usize {
+        wire().serialized_size(&Id::new(0, 0)).unwrap() as usize
+    }
+
+    // Read the header at `off` from exactly its own bytes, so it doesn't
+    // matter what follows it. Fails if the payload is too short or the bytes
+    // don't form a header (e.g. a flag other than 0 or 1).
+    fn parse(payload: &[u8], off: usize) -> Result<Self, Error> {
+        let malformed = || Error::MalformedFrame(payload.len());
+        let end = off.checked_add(Id::size()).ok_or_else(malformed)?;
+        let bytes = payload.get(off..end).ok_or_else(malformed)?;
+        wire().deserialize(bytes).map_err(|_| malformed())
+    }
+
+    fn next(self) -> Self {
+        Self {
+            id: self.id,
+            cnt: self.cnt.wrapping_add(1),
+            last: false,
+            probe: self.probe,
+            stamped: false,
+            ts_ns: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sample {
+    ts: Instant,
+    seq: Seq,
+    bytes: u64,  // Including frames skipped by --sample since the previous one
+    frames: u64, // Frames this sample stands for, 1 when not sampling
+}
+
+// Frames received from the first to the last sample of `chunk`, inclusive. The
+// first sample's own skipped frames precede it and are left out.
+fn frames_in(chunk: &[Sample]) -> u64 {
+    1 + chunk[1..].iter().map(|p| p.frames).sum::<u64>()
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Interval,
+    Summary,
+    Rolling, // Cumulative like a summary, but the stream goes on
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Dir {
+    Tx,
+    Rx,
+}
+
+// Phase of a --probe-secs test, reported stats cover a single phase
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Phase {
+    Probe,
+    Sustained,
+}
+
+// Startup latencies of a stream, seconds since the process started
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Setup {
+    ready_secs: f32,
+    first_frame_secs: f32,
+}
+
+// Time RX spends on a frame from rx.next() returning until the tracker is
+// updated, see --rx-load. Close to the inter-arrival time means the
+// receiver can't keep up.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct RxLoad {
+    avg_ns: f64,
+    max_ns: f64,
+    samples: u64,
+    interarrival_ns: f64,
+}
+
+// Frames of one stream seen with a given VLAN tag. Drops are derived from the
+// sequence range seen on the VLAN, so they're only meaningful when each VLAN
+// carries a contiguous part of the stream.
+#[derive(Debug, Default)]
+struct VlanStat {
+    pkts: u64,
+    bytes: u64,
+    lowest: Seq,
+    highest: Seq,
+}
+
+impl VlanStat {
+    fn record(&mut self, seq: Seq, len: u64) {
+        if self.pkts == 0 || seq_before(seq, self.lowest) {
+            self.lowest = seq;
+        }
+        if self.pkts == 0 || seq_before(self.highest, seq) {
+            self.highest = seq;
+        }
+        self.pkts += 1;
+        self.bytes += len;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct EthertypeReport {
+    ethertype: u16,
+    pkts: u64,
+    expected: u64,
+    dropped_pct: f32,
+}
+
+// Per-ethertype breakdown of an --ethertype-sweep stream
+#[derive(Debug, Clone, Serialize)]
+struct EthertypeSweepReport {
+    seen: u64,  // Ethertypes with at least one frame
+    total: u64, // Ethertypes in the range
+    ethertypes: Vec<EthertypeReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct VlanReport {
+    vlan: Option<u16>, // None for untagged frames
+    pkts: u64,
+    expected: u64,
+    dropped_pct: f32,
+    rate_bps: f32,
+}
+
+// --bufferbloat stamps frames by time rather than count, so the idle and the
+// loaded phase get the same number of latency samples. Stamped frames are
+// ordinary frames of the stream and queue (and get dropped) like the rest.
+const BUFFERBLOAT_STAMP_INTERVAL: Duration = Duration::from_millis(10);
+const BUFFERBLOAT_IDLE_SECS: u64 = 5;
+
+// Latency of the probe (idle) phase against the sustained (loaded) one
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Bufferbloat {
+    idle_p50_us: f64,
+    loaded_p50_us: f64,
+    idle_p99_us: f64,
+    loaded_p99_us: f64,
+    inflation: f64, // Of the median
+}
+
+impl Bufferbloat {
+    fn new(idle: Option<Latency>, loaded: Option<Latency>) -> Option<Self> {
+        let (idle, loaded) = (idle?, loaded?);
+        Some(Self {
+            idle_p50_us: idle.p50_us,
+            loaded_p50_us: loaded.p50_us,
+            idle_p99_us: idle.p99_us,
+            loaded_p99_us: loaded.p99_us,
+            inflation: loaded.p50_us / idle.p50_us,
+        })
+    }
+}
+
+// Per-frame bytes a link carries besides the payload: Ethernet header (14),
+// FCS (4), preamble and SFD (8) and the inter-frame gap (12). Payloads below
+// MIN_PAYLOAD are padded.
+const WIRE_OVERHEAD: f32 = 38.0;
+const MIN_PAYLOAD: f32 = 46.0;
+
+// Share of --link-speed used, by the payload rate and by what's on the wire
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Utilization {
+    link_bps: f32,
+    payload_pct: f32,
+    wire_pct: f32,
+}
+
+impl Utilization {
+    fn new(report: &Report, link_bps: f32) -> Option<Self> {
+        let secs = report.end - report.start;
+        if report.pkts == 0 || secs <= 0.0 {
+            return None;
+        }
+        let pps = report.pkts as f32 / secs;
+        let payload = (report.rate_bps / 8.0 / pps).max(MIN_PAYLOAD);
+        let wire_bps = pps * (payload + WIRE_OVERHEAD) * 8.0;
+        Some(Self {
+            link_bps,
+            payload_pct: report.rate_bps / link_bps * 100.0,
+            wire_pct: wire_bps / link_bps * 100.0,
+        })
+    }
+}
+
+// Inter-arrival times of a stream's frames in log-linear buckets, eight per
+// power of two, so memory is bounded and percentiles are within 12.5%
+#[derive(Debug)]
+struct GapHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_ns: u64,
+    max_ns: u64,
+    min: Option<(u64, u64)>, // Tightest gap and the bytes of the frame closing it
+    last: Option<Instant>,
+}
+
+impl GapHistogram {
+    const SUB_BITS: u32 = 3;
+    const BUCKETS: usize = (64 - Self::SUB_BITS as usize + 1) << Self::SUB_BITS;
+
+    fn new() -> Self {
+        Self {
+            counts: vec![0; Self::BUCKETS],
+            total: 0,
+            sum_ns: 0,
+            max_ns: 0,
+            min: None,
+            last: None,
+        }
+    }
+
+    fn bucket(ns: u64) -> usize {
+        let sub = 1 << Self::SUB_BITS;
+        if ns < sub {
+            return ns as usize;
+        }
+        let octave = 63 - ns.leading_zeros();
+        let shift = octave - Self::SUB_BITS;
+        (((shift + 1) << Self::SUB_BITS) as u64 + ((ns >> shift) & (sub - 1))) as usize
+    }
+
+    // Largest gap that falls into `bucket`
+    fn upper_ns(bucket: usize) -> u64 {
+        let sub = 1 << Self::SUB_BITS;
+        if bucket < sub {
+            return bucket as u64;
+        }
+        let shift = (bucket >> Self::SUB_BITS) as u32 - 1;
+        let low = ((sub + (bucket & (sub - 1))) as u64) << shift;
+        low + ((1 << shift) - 1)
+    }
+
+    fn record(&mut self, at: Instant, bytes: u64) {
+        if let Some(last) = self.last {
+            let ns = at.duration_since(last).as_nanos() as u64;
+            self.counts[Self::bucket(ns)] += 1;
+            self.total += 1;
+            self.sum_ns += ns;
+            self.max_ns = self.max_ns.max(ns);
+            if self.min.is_none_or(|(min_ns, _)| ns < min_ns) {
+                self.min = Some((ns, bytes));
+            }
+        }
+        self.last = Some(at);
+    }
+
+    fn percentile_ns(&self, q: f64) -> u64 {
+        let target = ((self.total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Self::upper_ns(bucket).min(self.max_ns);
+            }
+        }
+        self.max_ns
+    }
+
+    fn gaps(&self) -> Option<Gaps> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(Gaps {
+            avg_us: self.sum_ns as f64 / self.total as f64 / 1e3,
+            p99_us: self.percentile_ns(0.99) as f64 / 1e3,
+            p999_us: self.percentile_ns(0.999) as f64 / 1e3,
+            max_us: self.max_ns as f64 / 1e3,
+            min_us: self.min.map_or(0.0, |(ns, _)| ns as f64 / 1e3),
+            // Frames read in the same instant have no rate to speak of
+            burst_bps: self
+                .min
+                .filter(|&(ns, _)| ns > 0)
+                .map(|(ns, bytes)| (8 * bytes) as f32 / (ns as f32 / 1e9)),
+        })
+    }
+}
+
+// Tail of the inter-arrival times, a large p99.9 next to a small average
+// points at microbursts or stalls
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Gaps {
+    avg_us: f64,
+    p99_us: f64,
+    p999_us: f64,
+    max_us: f64,
+    min_us: f64,
+    // A frame over the tightest gap, what the buffers in front of the
+    // receiver had to absorb at the peak
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst_bps: Option<f32>,
+}
+
+// Averaging factors of --allan, in frames
+const ALLAN_FACTORS: [u64; 5] = [1, 10, 100, 1000, 10000];
+
+// Pairs of averages below this make a deviation too noisy to report
+const ALLAN_MIN_PAIRS: u64 = 3;
+
+// Allan deviation of the inter-arrival times, streamed: for each averaging
+// factor m the gaps are averaged over consecutive blocks of m, and half the
+// mean squared difference of neighbouring block averages is the variance.
+// Memory doesn't grow with the test.
+#[derive(Debug)]
+struct AllanDev {
+    last: Option<Instant>,
+    gaps: u64,
+    sum_ns: f64,
+    blocks: Vec<AllanBlock>,
+}
+
+#[derive(Debug, Default)]
+struct AllanBlock {
+    factor: u64,
+    sum_ns: f64, // Of the block being filled
+    len: u64,
+    prev_avg_ns: Option<f64>,
+    sq_diffs: f64,
+    pairs: u64,
+}
+
+impl AllanDev {
+    fn new() -> Self {
+        Self {
+            last: None,
+            gaps: 0,
+            sum_ns: 0.0,
+            blocks: ALLAN_FACTORS
+                .iter()
+                .map(|&factor| AllanBlock {
+                    factor,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    fn record(&mut self, at: Instant) {
+        if let Some(last) = self.last {
+            let gap_ns = at.duration_since(last).as_nanos() as f64;
+            self.gaps += 1;
+            self.sum_ns += gap_ns;
+            for b in &mut self.blocks {
+                b.sum_ns += gap_ns;
+                b.len += 1;
+                if b.len == b.factor {
+                    let avg = b.sum_ns / b.factor as f64;
+                    if let Some(prev) = b.prev_avg_ns {
+                        b.sq_diffs += (avg - prev).powi(2);
+                        b.pairs += 1;
+                    }
+                    b.prev_avg_ns = Some(avg);
+                    b.sum_ns = 0.0;
+                    b.len = 0;
+                }
+            }
+        }
+        self.last = Some(at);
+    }
+
+    fn points(&self) -> Option<Vec<AllanPoint>> {
+        if self.gaps == 0 {
+            return None;
+        }
+        let avg_gap_us = self.sum_ns / self.gaps as f64 / 1e3;
+        let points: Vec<AllanPoint> = self
+            .blocks
+            .iter()
+            .filter(|b| b.pairs >= ALLAN_MIN_PAIRS)
+            .map(|b| AllanPoint {
+                frames: b.factor,
+                tau_us: avg_gap_us * b.factor as f64,
+                adev_us: (b.sq_diffs / b.pairs as f64 / 2.0).sqrt() / 1e3,
+                pairs: b.pairs,
+            })
+            .collect();
+        Some(points).filter(|p| !p.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AllanPoint {
+    frames: u64,  // Averaging factor
+    tau_us: f64,  // The same as a time, at the average gap
+    adev_us: f64, // Allan deviation of the averaged gaps
+    pairs: u64,
+}
+
+// Frame delay variation without synced clocks: each frame's arrival is
+// compared with its slot in the schedule of a constant-rate sender, starting
+// at the first frame and one frame time per sequence number. The earliest
+// frame relative to its slot sets the zero, as it had the least delay.
+#[derive(Debug)]
+struct FdvClock {
+    rate_bps: f64,
+    start: Option<(Instant, Seq, f64)>, // First frame and the frame time in ns
+    sum_ns: f64,
+    count: u64,
+    min_ns: f64,
+    max_ns: f64,
+}
+
+impl FdvClock {
+    fn new(rate_bps: f64) -> Self {
+        Self {
+            rate_bps,
+            start: None,
+            sum_ns: 0.0,
+            count: 0,
+            min_ns: f64::MAX,
+            max_ns: f64::MIN,
+        }
+    }
+
+    fn record(&mut self, at: Instant, seq: Seq, len: u64) {
+        let rate_bps = self.rate_bps;
+        let (start, first, frame_ns) = *self
+            .start
+            .get_or_insert_with(|| (at, seq, (8 * len) as f64 / rate_bps * 1e9));
+        let slot_ns = seq.wrapping_sub(first) as i64 as f64 * frame_ns;
+        let offset_ns = if at >= start {
+            at.duration_since(start).as_nanos() as f64
+        } else {
+            -(start.duration_since(at).as_nanos() as f64)
+        } - slot_ns;
+        self.sum_ns += offset_ns;
+        self.count += 1;
+        self.min_ns = self.min_ns.min(offset_ns);
+        self.max_ns = self.max_ns.max(offset_ns);
+    }
+
+    // A pause moves the rest of the schedule back
+    fn shift(&mut self, paused: Duration) {
+        if let Some((start, _, _)) = &mut self.start {
+            *start += paused;
+        }
+    }
+
+    fn fdv(&self) -> Option<Fdv> {
+        if self.count < 2 {
+            return None;
+        }
+        Some(Fdv {
+            mean_us: (self.sum_ns / self.count as f64 - self.min_ns) / 1e3,
+            max_us: (self.max_ns - self.min_ns) / 1e3,
+            rate_bps: self.rate_bps as f32,
+        })
+    }
+}
+
+// Time from the sender stopping until the final frame arrived, which queued
+// behind everything still buffered on the path, see --drain-time. Unknown
+// when the final frame was lost.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Drain {
+    ms: Option<f64>,
+    lost_tail: Option<u64>, // Frames lost right before the final frame
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Fdv {
+    mean_us: f64,
+    max_us: f64,
+    rate_bps: f32, // Of the assumed schedule, see --fdv-rate
+}
+
+// One-way latency of the timestamped frames, in microseconds
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Latency {
+    samples: usize,
+    min_us: f64,
+    avg_us: f64,
+    max_us: f64,
+    p50_us: f64,
+    p99_us: f64,
+}
+
+// Frames that arrived shorter than sent, see --length-check. Counted as
+// received, so a path that cuts frames short shows no loss otherwise.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Truncation {
+    frames: u64,
+    avg_bytes_lost: f64,
+}
+
+// A single interval or summary report, printed as text or, with --json, as
+// one JSON object per line
+#[derive(Debug, Serialize)]
+struct Report {
+    #[serde(rename = "type")]
+    kind: Kind,
+    dir: Dir,
+    stream: u32, // Id.id of the stream
+    start: f32,
+    end: f32,
+    pkts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seq: Option<Seq>, // Id.cnt of the first frame to arrive, RX summaries only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seq: Option<Seq>, // And of the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped_pct: Option<f32>,
+    rate_bps: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    goodput_bps: Option<f32>, // Payload after the Id header of in-order, non-duplicate frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unique_rate_bps: Option<f32>, // Whole payload of non-duplicate frames, see --unique-rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drain: Option<Drain>,
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit, // Of the text output, JSON always carries bits per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outage_secs: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused_secs: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wrong_ethertype: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    corrupt: Option<u64>, // Frames off the --counter-payload pattern
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<Truncation>, // See --length-check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval_drops: Option<[usize; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lost: Option<Vec<(Seq, Seq)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lost_truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peak_pps: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_pps: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ipg_ns: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out_of_order: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    late: Option<u64>, // Past --reorder-window, counted as lost
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_dups: Option<u64>, // See --payload-dups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>, // The payload bytes behind rate_bps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ethertype: Option<u16>, // Of the stream, with --stream-ethertypes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule: Option<Adherence>, // See --schedule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bad_fcs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_errors: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    setup: Option<Setup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rx_thread: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_stream: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rt_priority: Option<RtPriority>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restart_of: Option<u32>, // Stream of the same sender before it restarted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restarted: Option<bool>, // Ended by a sender restart rather than a sentinel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rx_load: Option<RxLoad>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<Latency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlans: Option<Vec<VlanReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ethertype_sweep: Option<EthertypeSweepReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bufferbloat: Option<Bufferbloat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    utilization: Option<Utilization>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gaps: Option<Gaps>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    injected_delay_us: Option<f64>, // See --inject-delay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fdv: Option<Fdv>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allan: Option<Vec<AllanPoint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<Phase>,
+}
+
+impl Report {
+    fn new(kind: Kind, dir: Dir, stream: u32) -> Self {
+        Self {
+            kind,
+            dir,
+            stream,
+            start: 0.0,
+            end: 0.0,
+            pkts: 0,
+            first_seq: None,
+            last_seq: None,
+            expected: None,
+            dropped_pct: None,
+            rate_bps: 0.0,
+            goodput_bps: None,
+            unique_rate_bps: None,
+            drain: None,
+            unit: Unit::Mbps,
+            outage_secs: None,
+            paused_secs: None,
+            wrong_ethertype: None,
+            corrupt: None,
+            truncated: None,
+            interval_drops: None,
+            lost: None,
+            lost_truncated: None,
+            peak_pps: None,
+            avg_pps: None,
+            avg_ipg_ns: None,
+            out_of_order: None,
+            late: None,
+            duplicates: None,
+            payload_dups: None,
+            bytes: None,
+            ethertype: None,
+            schedule: None,
+            bad_fcs: None,
+            send_errors: None,
+            setup: None,
+            rx_thread: None,
+            tx_stream: None,
+            rt_priority: None,
+            restart_of: None,
+            restarted: None,
+            rx_load: None,
+            latency: None,
+            vlans: None,
+            ethertype_sweep: None,
+            bufferbloat: None,
+            utilization: None,
+            gaps: None,
+            fdv: None,
+            allan: None,
+            injected_delay_us: None,
+            phase: None,
+        }
+    }
+}
+
+// Cells of the first line of a text report, see --columns. A column without
+// a value for the report (drops of a TX report, latency without timestamps...)
+// is left out.
+type Column = fn(&Report) -> Option<String>;
+
+const COLUMNS: &[(&str, Column)] = &[
+    ("time", |r| {
+        Some(format!("Sec: {}-{}", Num(r.start, 2), Num(r.end, 2)))
+    }),
+    ("pkts", |r| {
+        Some(match (r.dir, r.expected) {
+            (Dir::Tx, _) => format!("Sent: {} pkts", r.pkts),
+            (Dir::Rx, Some(expected)) => format!("Recv: {}/{} pkts", r.pkts, expected),
+            // --raw-count
+            (Dir::Rx, None) => format!("Recv: {} pkts", r.pkts),
+        })
+    }),
+    ("drops", |r| match r.dir {
+        Dir::Tx => None,
+        Dir::Rx => r
+            .dropped_pct
+            .map(|pct| format!("Dropped: {}%", Num(pct, 2))),
+    }),
+    ("rate", |r| {
+        Some(format!(
+            "Rate: {} {}",
+            Num(r.rate_bps / r.unit.divisor(), 2),
+            r.unit
+        ))
+    }),
+    ("goodput", |r| match r.kind {
+        // Summaries have a line of their own
+        Kind::Interval => r
+            .goodput_bps
+            .map(|goodput| format!("Goodput: {} {}", Num(goodput / r.unit.divisor(), 2), r.unit)),
+        _ => None,
+    }),
+    ("pps", |r| {
+        Some(r.end - r.start)
+            .filter(|&secs| secs > 0.0)
+            .map(|secs| format!("PPS: {}", Num(r.pkts as f32 / secs, 0)))
+    }),
+    ("jitter", |r| {
+        r.fdv
+            .map(|d| format!("Jitter: {} us", Num(d.mean_us, 1)))
+            .or_else(|| r.gaps.map(|g| format!("Gap p99: {} us", Num(g.p99_us, 1))))
+    }),
+    ("latency", |r| {
+        r.latency
+            .map(|l| format!("Latency p50: {} us", Num(l.p50_us, 1)))
+    }),
+];
+
+// The columns l2perf has always printed, goodput only shows in intervals of
+// streams that are checked
+const DEFAULT_COLUMNS: &[Column] = &[
+    COLUMNS[0].1,
+    COLUMNS[1].1,
+    COLUMNS[2].1,
+    COLUMNS[3].1,
+    COLUMNS[4].1,
+];
+
+// Set from --columns
+static COLUMNS_SHOWN: OnceLock<Vec<Column>> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+struct Columns(Vec<String>);
+
+fn parse_columns(src: &str) -> Result<Columns, String> {
+    let names: Vec<String> = src.split(',').map(|s| s.trim().to_lowercase()).collect();
+    for name in &names {
+        if !COLUMNS.iter().any(|(n, _)| n == name) {
+            let known: Vec<&str> = COLUMNS.iter().map(|(n, _)| *n).collect();
+            return Err(format!(
+                "unknown column \"{}\", expected some of {}",
+                name,
+                known.join(", ")
+            ));
+        }
+    }
+    Ok(Columns(names))
+}
+
+impl Columns {
+    fn formatters(&self) -> Vec<Column> {
+        self.0
+            .iter()
+            .filter_map(|name| COLUMNS.iter().find(|(n, _)| n == name).map(|(_, c)| *c))
+            .collect()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Kind::Rolling = self.kind {
+            writeln!(f, "Rolling summary:")?;
+        }
+        if let Kind::Summary = self.kind {
+            match self.phase {
+                Some(Phase::Probe) => writeln!(f, "Probe summary:")?,
+                Some(Phase::Sustained) => writeln!(f, "Sustained summary:")?,
+                None => writeln!(f, "Summary:")?,
+            }
+        }
+        let columns = COLUMNS_SHOWN.get().map_or(DEFAULT_COLUMNS, |c| &c[..]);
+        let cells: Vec<String> = columns.iter().filter_map(|c| c(self)).collect();
+        write!(f, "{}", cells.join(", "))?;
+        if let Some(bytes) = self.bytes.filter(|_| raw_numbers()) {
+            write!(f, "\nBytes: {} ({} bits)", bytes, 8 * bytes)?;
+        }
+
+        if let (Some(first), Some(last)) = (self.first_seq, self.last_seq) {
+            write!(
+                f,
+                "\nStream: {:x}, first seq: {}, last seq: {}",
+                self.stream, first, last
+            )?;
+        }
+        if let Some(ethertype) = self.ethertype {
+            write!(
+                f,
+                "\nEther Type: {:x}{}",
+                ethertype,
+                ethertype_label(ethertype)
+            )?;
+        }
+        if let Some(goodput) = self
+            .goodput_bps
+            .filter(|_| !matches!(self.kind, Kind::Interval))
+        {
+            write!(
+                f,
+                "\nGoodput: {} {}",
+                Num(goodput / self.unit.divisor(), 2),
+                self.unit
+            )?;
+        }
+        if let Some(unique) = self.unique_rate_bps {
+            write!(
+                f,
+                "\nUnique rate: {} {}",
+                Num(unique / self.unit.divisor(), 2),
+                self.unit
+            )?;
+        }
+        match self.drain {
+            Some(Drain {
+                ms: Some(ms),
+                lost_tail,
+            }) => {
+                write!(f, "\nDrain time: {} ms", Num(ms, 3))?;
+                if let Some(lost) = lost_tail.filter(|&n| n > 0) {
+                    write!(
+                        f,
+                        " (the last {} frames were lost, they may have drained later)",
+                        lost
+                    )?;
+                }
+            }
+            Some(Drain { ms: None, .. }) => {
+                write!(f, "\nDrain time: unknown, the final frame was lost")?
+            }
+            None => {}
+        }
+        if let Some(outage) = self.outage_secs {
+            write!(f, "\nLink down: {}s", Num(outage, 2))?;
+        }
+        if let Some(paused) = self.paused_secs {
+            write!(f, "\nPaused: {}s", Num(paused, 2))?;
+        }
+        if let Some(wrong) = self.wrong_ethertype {
+            write!(f, "\nWrong ethertype: {} frames", wrong)?;
+        }
+        if let Some(corrupt) = self.corrupt {
+            write!(f, "\nCorrupted payload: {} frames", corrupt)?;
+        }
+        if let Some(t) = self.truncated {
+            write!(f, "\nTruncated: {} frames", t.frames)?;
+            if t.frames > 0 {
+                write!(f, ", {} bytes short on average", Num(t.avg_bytes_lost, 1))?;
+            }
+        }
+        if let Some(b) = self.interval_drops {
+            write!(
+                f,
+                "\nInterval drops ({} intervals): 0%: {}, <1%: {}, 1-5%: {}, >5%: {}",
+                b.iter().sum::<usize>(),
+                b[0],
+                b[1],
+                b[2],
+                b[3]
+            )?;
+        }
+        if let Some(lost) = &self.lost {
+            let mut ranges: Vec<String> = lost
+                .iter()
+                .map(|&(a, b)| {
+                    if a == b {
+                        a.to_string()
+                    } else {
+                        format!("{}-{}", a, b)
+                    }
+                })
+                .collect();
+            if self.lost_truncated.is_some() {
+                ranges.push("...".to_string());
+            }
+            if ranges.is_empty() {
+                ranges.push("none".to_string());
+            }
+            write!(f, "\nLost: {}", ranges.join(", "))?;
+        }
+        if let (Some(peak), Some(avg)) = (self.peak_pps, self.avg_pps) {
+            write!(
+                f,
+                "\nPeak: {} pps, Average: {} pps",
+                Num(peak, 0),
+                Num(avg, 0)
+            )?;
+        }
+        if let Some(ipg) = self.avg_ipg_ns {
+            write!(f, "\nAverage IPG: {} ns", Num(ipg, 0))?;
+        }
+        if let Some(n) = self.out_of_order {
+            write!(f, "\nOut of order: {} frames", n)?;
+        }
+        if let Some(n) = self.late {
+            write!(f, "\nLate: {} frames, counted as lost", n)?;
+        }
+        if let Some(n) = self.duplicates {
+            write!(f, "\nDuplicates: {} frames", n)?;
+        }
+        if let Some(n) = self.payload_dups {
+            write!(
+                f,
+                "\nIdentical payloads: {} frames (among the last {})",
+                n, PAYLOAD_WINDOW
+            )?;
+        }
+        if let Some(bad) = self.bad_fcs {
+            write!(f, "\nBad FCS: {} frames", bad)?;
+        }
+        if let Some(errors) = self.send_errors {
+            write!(f, "\nSend errors: {}", errors)?;
+        }
+        if let Some(s) = self.schedule {
+            write!(
+                f,
+                "\nSchedule: {} frames, {} us late on average, {} us at most, {} more than 1ms late",
+                s.frames,
+                Num(s.mean_error_us, 1),
+                Num(s.max_error_us, 1),
+                s.late_1ms
+            )?;
+        }
+        if let Some(thread) = self.rx_thread {
+            write!(f, "\nRX thread: {}", thread)?;
+        }
+        if let Some(stream) = self.tx_stream {
+            write!(f, "\nTX stream: {}", stream)?;
+        }
+        if let Some(rt) = self.rt_priority {
+            let granted = if rt.granted { "granted" } else { "not granted" };
+            write!(f, "\nRT priority: SCHED_FIFO {}, {}", rt.priority, granted)?;
+        }
+        if let Some(old) = self.restart_of {
+            write!(f, "\nSender restarted, follows stream {:x}", old)?;
+        }
+        if self.restarted == Some(true) {
+            write!(f, "\nEnded by a sender restart")?;
+        }
+        if let Some(setup) = self.setup {
+            write!(
+                f,
+                "\nSetup: channel ready {}s, first frame {}s after start",
+                Num(setup.ready_secs, 3),
+                Num(setup.first_frame_secs, 3)
+            )?;
+        }
+        for v in self.vlans.iter().flatten() {
+            match v.vlan {
+                Some(vlan) => write!(f, "\nVLAN {}: ", vlan)?,
+                None => write!(f, "\nNo VLAN: ")?,
+            }
+            write!(
+                f,
+                "Recv: {}/{} pkts, Dropped: {}%, Rate: {} {}",
+                v.pkts,
+                v.expected,
+                Num(v.dropped_pct, 2),
+                Num(v.rate_bps / self.unit.divisor(), 2),
+                self.unit
+            )?;
+        }
+        if let Some(sweep) = &self.ethertype_sweep {
+            write!(f, "\nEther Types: {}/{} seen", sweep.seen, sweep.total)?;
+            if sweep.total <= MAX_ETHERTYPE_LINES {
+                for e in &sweep.ethertypes {
+                    write!(
+                        f,
+                        "\nEther Type {:x}: Recv: {}/{} pkts, Dropped: {}%",
+                        e.ethertype,
+                        e.pkts,
+                        e.expected,
+                        Num(e.dropped_pct, 2)
+                    )?;
+                }
+            }
+        }
+        if let Some(l) = self.latency {
+            write!(
+                f,
+                "\nLatency: {}/{}/{} us min/avg/max, p50: {} us, p99: {} us ({} samples)",
+                Num(l.min_us, 1),
+                Num(l.avg_us, 1),
+                Num(l.max_us, 1),
+                Num(l.p50_us, 1),
+                Num(l.p99_us, 1),
+                l.samples
+            )?;
+        }
+        if let Some(g) = self.gaps {
+            write!(
+                f,
+                "\nInter-arrival: {} us avg, p99: {} us, p99.9: {} us, max: {} us",
+                Num(g.avg_us, 1),
+                Num(g.p99_us, 1),
+                Num(g.p999_us, 1),
+                Num(g.max_us, 1)
+            )?;
+            write!(f, "\nTightest gap: {} us", Num(g.min_us, 3))?;
+            if let Some(burst) = g.burst_bps {
+                write!(
+                    f,
+                    ", a burst rate of {} {}",
+                    Num(burst / self.unit.divisor(), 2),
+                    self.unit
+                )?;
+            }
+        }
+        if let Some(points) = &self.allan {
+            write!(f, "\nAllan deviation of the inter-arrival times:")?;
+            write!(
+                f,
+                "\n{:>8} {:>12} {:>12}",
+                "Frames", "Tau (us)", "ADEV (us)"
+            )?;
+            for p in points {
+                write!(
+                    f,
+                    "\n{:>8} {:>12} {:>12}",
+                    p.frames,
+                    Num(p.tau_us, 1),
+                    Num(p.adev_us, 3)
+                )?;
+            }
+        }
+        if let (Some(injected), Some(l)) = (self.injected_delay_us, self.latency) {
+            write!(
+                f,
+                "\nInjected delay: {} us, measured {} us avg ({:+} us)",
+                Num(injected, 1),
+                Num(l.avg_us, 1),
+                Num(l.avg_us - injected, 1)
+            )?;
+        }
+        if let Some(d) = self.fdv {
+            write!(
+                f,
+                "\nDelay variation: {} us mean, {} us max, against {} {}",
+                Num(d.mean_us, 1),
+                Num(d.max_us, 1),
+                Num(d.rate_bps / self.unit.divisor(), 2),
+                self.unit
+            )?;
+        }
+        if let Some(u) = self.utilization {
+            write!(
+                f,
+                "\nUtilization: {}% of the {} {} link, {}% on the wire",
+                Num(u.payload_pct, 1),
+                Num(u.link_bps / self.unit.divisor(), 2),
+                self.unit,
+                Num(u.wire_pct, 1)
+            )?;
+        }
+        if let Some(b) = self.bufferbloat {
+            write!(
+                f,
+                "\nBufferbloat: p50 {} -> {} us ({}x), p99 {} -> {} us, idle -> loaded",
+                Num(b.idle_p50_us, 1),
+                Num(b.loaded_p50_us, 1),
+                Num(b.inflation, 1),
+                Num(b.idle_p99_us, 1),
+                Num(b.loaded_p99_us, 1)
+            )?;
+        }
+        if let Some(load) = self.rx_load {
+            write!(
+                f,
+                "\nReceiver load: {} ns avg, {} ns max per frame ({} sampled), {}% of the inter-arrival time",
+                Num(load.avg_ns, 0),
+                Num(load.max_ns, 0),
+                load.samples,
+                Num(100.0 * load.avg_ns / load.interarrival_ns, 1)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Comparison of the two directions of a --bidir test
+#[derive(Debug, Serialize)]
+struct Asymmetry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    forward: String, // "tx_ifname -> rx_ifname"
+    reverse: String,
+    forward_psize: usize,
+    reverse_psize: usize,
+    forward_rate_bps: f32, // Received
+    reverse_rate_bps: f32,
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit,
+    forward_dropped_pct: f32,
+    reverse_dropped_pct: f32,
+    asymmetry_pct: f32, // Absolute difference in percentage points
+    asymmetric: bool,
+    // With --timestamp-every. Both directions run on this host, so their
+    // one-way latencies come from the same clock and compare directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_latency: Option<Latency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverse_latency: Option<Latency>,
+}
+
+impl fmt::Display for Asymmetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Bidirectional:")?;
+        writeln!(
+            f,
+            "{}: Payload: {} bytes, Rate: {} {}, Dropped: {}%",
+            self.forward,
+            self.forward_psize,
+            Num(self.forward_rate_bps / self.unit.divisor(), 2),
+            self.unit,
+            Num(self.forward_dropped_pct, 2)
+        )?;
+        writeln!(
+            f,
+            "{}: Payload: {} bytes, Rate: {} {}, Dropped: {}%",
+            self.reverse,
+            self.reverse_psize,
+            Num(self.reverse_rate_bps / self.unit.divisor(), 2),
+            self.unit,
+            Num(self.reverse_dropped_pct, 2)
+        )?;
+        write!(f, "Asymmetry: {} points", Num(self.asymmetry_pct, 2))?;
+        if self.asymmetric {
+            write!(f, "\nAsymmetric loss detected")?;
+        }
+        for (name, latency) in [
+            (&self.forward, self.forward_latency),
+            (&self.reverse, self.reverse_latency),
+        ] {
+            if let Some(l) = latency {
+                write!(
+                    f,
+                    "\n{}: Latency: p50 {} us, p99 {} us, max {} us",
+                    name,
+                    Num(l.p50_us, 1),
+                    Num(l.p99_us, 1),
+                    Num(l.max_us, 1)
+                )?;
+            }
+        }
+        match (self.forward_latency, self.reverse_latency) {
+            (Some(fwd), Some(rev)) => write!(
+                f,
+                "\nLatency asymmetry: forward {:+} us p50, {:+} us p99 over reverse",
+                Num(fwd.p50_us - rev.p50_us, 1),
+                Num(fwd.p99_us - rev.p99_us, 1)
+            )?,
+            (Some(_), None) | (None, Some(_)) => write!(
+                f,
+                "\nLatency asymmetry: no timestamped frames arrived in one direction"
+            )?,
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+// Headline numbers of a finished run, for --repeat and --bidir
+#[derive(Debug, Clone, Copy)]
+struct RunResult {
+    rate_bps: f32,
+    dropped_pct: Option<f32>, // None on the TX side
+    pkts: u64,
+    secs: f32,
+    latency: Option<Latency>, // RX side, of the timestamped frames
+}
+
+// Streams summarized by the --rx-threads workers, tagged with the thread index
+static RX_RESULTS: Mutex<Option<mpsc::Sender<(usize, RunResult)>>> = Mutex::new(None);
+
+// Per-thread packet counts of the streams seen across --rx-threads workers
+#[derive(Debug, Serialize)]
+struct ThreadTotals {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    threads: Vec<ThreadStat>,
+    total_pkts: u64,
+    total_pps: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct ThreadStat {
+    thread: usize,
+    pkts: u64,
+    pps: f32, // Sum over the thread's streams, they may overlap in time
+}
+
+impl ThreadTotals {
+    fn new(threads: usize, results: &[(usize, RunResult)]) -> Self {
+        let threads: Vec<ThreadStat> = (0..threads)
+            .map(|thread| {
+                let mine = results.iter().filter(|(t, _)| *t == thread);
+                ThreadStat {
+                    thread,
+                    pkts: mine.clone().map(|(_, r)| r.pkts).sum(),
+                    pps: mine
+                        .filter(|(_, r)| r.secs > 0.0)
+                        .fold(0.0, |pps, (_, r)| pps + r.pkts as f32 / r.secs),
+                }
+            })
+            .collect();
+        Self {
+            kind: "rx_threads",
+            total_pkts: threads.iter().map(|t| t.pkts).sum(),
+            total_pps: threads.iter().fold(0.0, |pps, t| pps + t.pps),
+            threads,
+        }
+    }
+}
+
+impl fmt::Display for ThreadTotals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RX threads:")?;
+        for t in &self.threads {
+            writeln!(
+                f,
+                "Thread {}: {} pkts, {} pps",
+                t.thread,
+                t.pkts,
+                Num(t.pps, 0)
+            )?;
+        }
+        write!(
+            f,
+            "Total: {} pkts, {} pps",
+            self.total_pkts,
+            Num(self.total_pps, 0)
+        )
+    }
+}
+
+// Per-stream and combined rates of a --parallel test
+#[derive(Debug, Serialize)]
+struct ParallelTotals {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    streams: Vec<StreamStat>,
+    total_pkts: u64,
+    total_rate_bps: f32,
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamStat {
+    stream: usize,
+    cpu: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stagger_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ethertype: Option<u16>, // With --stream-ethertypes
+    pkts: u64,
+    rate_bps: f32,
+}
+
+impl fmt::Display for ParallelTotals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Parallel streams:")?;
+        for s in &self.streams {
+            write!(f, "Stream {} (CPU {}", s.stream, s.cpu)?;
+            if let Some(ethertype) = s.ethertype {
+                write!(f, ", Ether Type {:x}", ethertype)?;
+            }
+            if let Some(ms) = s.stagger_ms {
+                write!(f, ", +{} ms", Num(ms, 2))?;
+            }
+            writeln!(
+                f,
+                "): {} pkts, {} {}",
+                s.pkts,
+                Num(s.rate_bps / self.unit.divisor(), 2),
+                self.unit
+            )?;
+        }
+        write!(
+            f,
+            "Total: {} pkts, {} {}",
+            self.total_pkts,
+            Num(self.total_rate_bps / self.unit.divisor(), 2),
+            self.unit
+        )
+    }
+}
+
+// Results of a --size-sweep, one row per payload size
+#[derive(Debug, Serialize)]
+struct Sweep {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    points: Vec<SweepPoint>,
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit,
+}
+
+#[derive(Debug, Serialize)]
+struct SweepPoint {
+    psize: usize,
+    frame_size: usize, // Ethernet header included, no FCS
+    rate_bps: f32,
+    pps: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped_pct: Option<f32>,
+}
+
+impl fmt::Display for Sweep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let drops = self.points.iter().any(|p| p.dropped_pct.is_some());
+        writeln!(f, "Size sweep:")?;
+        write!(
+            f,
+            "{:>8} {:>8} {:>12} {:>12}",
+            "Payload",
+            "Frame",
+            self.unit.to_string(),
+            "PPS"
+        )?;
+        if drops {
+            write!(f, " {:>8}", "Dropped")?;
+        }
+        for p in &self.points {
+            write!(
+                f,
+                "\n{:>8} {:>8} {:>12} {:>12}",
+                p.psize,
+                p.frame_size,
+                Num(p.rate_bps / self.unit.divisor(), 2),
+                Num(p.pps, 0)
+            )?;
+            if let Some(dropped) = p.dropped_pct {
+                write!(f, " {:>7}%", Num(dropped, 2))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Mean and sample standard deviation of `values`
+fn mean_stddev(values: &[f32]) -> (f32, f32) {
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    if values.len() < 2 {
+        return (mean, 0.0);
+    }
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / (n - 1.0);
+    (mean, var.sqrt())
+}
+
+// Statistics across the runs of a --repeat test
+#[derive(Debug, Serialize)]
+struct Aggregate {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    runs: usize,
+    rate_mean_bps: f32,
+    rate_stddev_bps: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped_mean_pct: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dropped_stddev_pct: Option<f32>,
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit,
+}
+
+impl Aggregate {
+    fn new(results: &[RunResult]) -> Self {
+        let rates: Vec<f32> = results.iter().map(|r| r.rate_bps).collect();
+        let (rate_mean_bps, rate_stddev_bps) = mean_stddev(&rates);
+        let drops: Vec<f32> = results.iter().filter_map(|r| r.dropped_pct).collect();
+        let (dropped_mean_pct, dropped_stddev_pct) = if drops.is_empty() {
+            (None, None)
+        } else {
+            let (mean, stddev) = mean_stddev(&drops);
+            (Some(mean), Some(stddev))
+        };
+        Self {
+            kind: "aggregate",
+            runs: results.len(),
+            rate_mean_bps,
+            rate_stddev_bps,
+            dropped_mean_pct,
+            dropped_stddev_pct,
+            unit: Unit::Mbps,
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Aggregate ({} runs):", self.runs)?;
+        write!(
+            f,
+            "Rate: {} {}, Stddev: {} {}",
+            Num(self.rate_mean_bps / self.unit.divisor(), 2),
+            self.unit,
+            Num(self.rate_stddev_bps / self.unit.divisor(), 2),
+            self.unit
+        )?;
+        if let (Some(mean), Some(stddev)) = (self.dropped_mean_pct, self.dropped_stddev_pct) {
+            write!(
+                f,
+                "\nDropped: {}%, Stddev: {}%",
+                Num(mean, 2),
+                Num(stddev, 2)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Cap on the ranges kept by --log-drops
+const MAX_DROP_RANGES: usize = 1024;
+
+// Missing sequence numbers of a stream, coalesced into inclusive ranges
+#[derive(Debug, Default)]
+struct DropLog {
+    highest: Option<Seq>,
+    ranges: Vec<(Seq, Seq)>,
+    truncated: bool,
+}
+
+impl DropLog {
+    fn record(&mut self, seq: Seq) {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                return;
+            }
+        };
+
+        if seq_before(highest, seq) {
+            let first = highest.wrapping_add(1);
+            if first != seq {
+                if self.ranges.len() < MAX_DROP_RANGES {
+                    self.ranges.push((first, seq.wrapping_sub(1)));
+                } else {
+                    self.truncated = true;
+                }
+            }
+            self.highest = Some(seq);
+        } else if let Some(i) = self
+            .ranges
+            .iter()
+            .rposition(|&(a, b)| !seq_before(seq, a) && !seq_before(b, seq))
+        {
+            // A late frame filling (part of) an earlier gap
+            let (a, b) = self.ranges[i];
+            if a == seq && b == seq {
+                self.ranges.remove(i);
+            } else if a == seq {
+                self.ranges[i].0 = seq.wrapping_add(1);
+            } else if b == seq {
+                self.ranges[i].1 = seq.wrapping_sub(1);
+            } else {
+                self.ranges[i].1 = seq.wrapping_sub(1);
+                self.ranges.insert(i + 1, (seq.wrapping_add(1), b));
+            }
+        }
+    }
+}
+
+// Payload hashes of the last PAYLOAD_WINDOW frames, see --payload-dups.
+// A frame copied on the way has the same Id too, so it's also a sequence
+// duplicate, unless the copy arrived too late or the sender repeats payloads.
+const PAYLOAD_WINDOW: usize = 4096;
+
+#[derive(Debug, Default)]
+struct PayloadWindow {
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+    copies: u64,
+}
+
+impl PayloadWindow {
+    fn record(&mut self, hash: u64) {
+        if self.seen.contains(&hash) {
+            self.copies += 1;
+            return;
+        }
+        if self.order.len() == PAYLOAD_WINDOW {
+            let oldest = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(hash);
+        self.seen.insert(hash);
+    }
+}
+
+// Sequence numbers received among the last SEQ_WINDOW, to tell duplicates
+// from late frames in bounded memory
+const SEQ_WINDOW: u64 = 1 << 16;
+
+#[derive(Debug)]
+struct SeqWindow {
+    bits: Vec<u64>,
+    highest: Option<Seq>,
+}
+
+impl SeqWindow {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; (SEQ_WINDOW / 64) as usize],
+            highest: None,
+        }
+    }
+
+    fn clear(&mut self, seq: Seq) {
+        self.bits[((seq % SEQ_WINDOW) / 64) as usize] &= !(1 << (seq % 64));
+    }
+
+    // Mark `seq` as received, true if it already was. Frames older than the
+    // window can't be told apart and count as late.
+    fn seen(&mut self, seq: Seq) -> bool {
+        match self.highest {
+            Some(highest) if seq_before(highest, seq) => {
+                let ahead = seq.wrapping_sub(highest);
+                if ahead >= SEQ_WINDOW {
+                    self.bits.fill(0);
+                } else {
+                    for s in 1..=ahead {
+                        self.clear(highest.wrapping_add(s));
+                    }
+                }
+                self.highest = Some(seq);
+            }
+            Some(highest) if highest.wrapping_sub(seq) >= SEQ_WINDOW => return false,
+            Some(_) => {}
+            None => self.highest = Some(seq),
+        }
+        let (word, bit) = (((seq % SEQ_WINDOW) / 64) as usize, seq % 64);
+        let was = self.bits[word] >> bit & 1 == 1;
+        self.bits[word] |= 1 << bit;
+        was
+    }
+}
+
+#[derive(Debug)]
+struct Tracker {
+    stream: u32,
+    begin: Instant,
+    total_bytes: u64,
+    last_ptr: usize,
+    last_rep: Instant,
+    pkts: Vec<Sample>,
+    out_of_order: u64, // Frames arriving after a higher sequence number
+    duplicates: u64,   // Counted by `window`, RX only
+    payloads: Option<PayloadWindow>,
+    duplicate_bytes: u64,
+    window: Option<SeqWindow>,
+    max_samples: Option<usize>, // See --max-samples
+    min_interval_pkts: u64,     // See --min-interval-pkts, RX only
+    src: Option<MacAddr>,       // Sender, raw capture only
+    restart_of: Option<u32>,
+    restart_candidates: Vec<u32>, // See take_restarted
+    restarted: bool,
+    capped: bool,                      // Reached max_samples, pkts only holds totals
+    interval_drops: Vec<f32>,          // Drop percentage of each reported interval
+    interval_seqs: Option<(Seq, Seq)>, // Lowest and highest of the current interval
+    peak_pps: f32,
+    pending_bytes: u64, // Skipped by --sample, folded into the next sample
+    pending_frames: u64,
+    outage: Duration, // Time the link was down
+    paused: Duration, // Left out of the rates, see Pause
+    drop_log: Option<DropLog>,
+    phase: Option<Phase>, // None in single-phase tests
+    last_rolling: Instant,
+    highest: Option<Seq>, // Highest sequence number received
+    lowest: Option<Seq>,
+    reorder_window: Option<u64>, // See --reorder-window, RX only
+    late: u64,
+    final_frame: Option<(Seq, i128)>, // Its sequence and latency in ns, see --drain-time
+    good_bytes: u64,
+    interval_goodput: bool, // Goodput in interval reports too, RX only
+    rep_good_bytes: u64,    // good_bytes at the last interval report
+    bad_fcs: u64,           // Frames sent with a corrupted FCS, see --bad-fcs
+    corrupt: u64,           // Frames off the --counter-payload pattern
+    truncated: (u64, u64),  // Frames that arrived shorter and the bytes missing
+    setup: Option<Setup>,
+    proc_total: Duration, // Sampled per-frame processing time, see --rx-load
+    proc_max: Duration,
+    proc_samples: u64,
+    latency_us: Vec<f64>,                           // Of frames with a timestamp
+    idle_latency: Option<Latency>, // Of the probe phase, kept for the sustained one
+    vlans: Option<BTreeMap<Option<u16>, VlanStat>>, // By outer VLAN id, see --raw-capture
+    ethertypes: Option<BTreeMap<u16, VlanStat>>, // See --ethertype-sweep
+    ethertype: Option<u16>,        // Of the first frame, with --stream-ethertypes
+    gaps: Option<GapHistogram>,    // Between decoded frames, RX only
+    fdv: Option<FdvClock>,         // See --fdv-rate
+    allan: Option<AllanDev>,       // See --allan
+}
+
+impl Tracker {
+    pub fn new(stream: u32) -> Self {
+        Self {
+            stream,
+            begin: Instant::now(),
+            last_rep: Instant::now(),
+            last_ptr: 0,
+            total_bytes: 0,
+            pkts: vec![],
+            out_of_order: 0,
+            duplicates: 0,
+            payloads: None,
+            duplicate_bytes: 0,
+            window: None,
+            max_samples: None,
+            min_interval_pkts: 0,
+            src: None,
+            restart_of: None,
+            restart_candidates: vec![],
+            restarted: false,
+            capped: false,
+            interval_drops: vec![],
+            interval_seqs: None,
+            peak_pps: 0.0,
+            pending_bytes: 0,
+            pending_frames: 0,
+            outage: Duration::ZERO,
+            paused: Duration::ZERO,
+            drop_log: None,
+            phase: None,
+            last_rolling: Instant::now(),
+            highest: None,
+            lowest: None,
+            reorder_window: None,
+            late: 0,
+            final_frame: None,
+            good_bytes: 0,
+            interval_goodput: false,
+            rep_good_bytes: 0,
+            bad_fcs: 0,
+            corrupt: 0,
+            truncated: (0, 0),
+            setup: None,
+            proc_total: Duration::ZERO,
+            proc_max: Duration::ZERO,
+            proc_samples: 0,
+            latency_us: vec![],
+            idle_latency: None,
+            vlans: None,
+            ethertypes: None,
+            ethertype: None,
+            gaps: None,
+            fdv: None,
+            allan: None,
+        }
+    }
+
+    // Share of `rate_bps` left after taking out the duplicate frames
+    fn unique_rate(&self, rate_bps: f32) -> f32 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        let unique = self.total_bytes.saturating_sub(self.duplicate_bytes);
+        rate_bps * (unique as f64 / self.total_bytes as f64) as f32
+    }
+
+    // The final frame's latency is the drain time. Frames between the highest
+    // seen and the final one were lost, and may have taken longer.
+    fn drain(&self) -> Drain {
+        match self.final_frame {
+            Some((seq, latency_ns)) => Drain {
+                ms: Some(latency_ns as f64 / 1e6),
+                lost_tail: self.highest.map(|h| seq.wrapping_sub(h).saturating_sub(1)),
+            },
+            None => Drain {
+                ms: None,
+                lost_tail: None,
+            },
+        }
+    }
+
+    // Returns whether the frame counted as goodput
+    fn insert(&mut self, id: &Id, len: u64) -> bool {
+        if let Some(last) = self.pkts.last() {
+            if seq_before(id.cnt, last.seq) {
+                if self.out_of_order == 0 {
+                    clear_progress();
+                    eprintln!("Out of order recv!");
+                }
+                self.out_of_order += 1;
+            }
+        }
+        if let Some(log) = &mut self.drop_log {
+            log.record(id.cnt);
+        }
+        let mut duplicate = false;
+        if let Some(window) = &mut self.window {
+            if window.seen(id.cnt) {
+                duplicate = true;
+                self.duplicates += 1;
+                self.duplicate_bytes += len;
+            }
+        }
+        if let (Some(tolerance), Some(highest)) = (self.reorder_window, self.highest) {
+            if !duplicate && seq_before(id.cnt, highest) && highest.wrapping_sub(id.cnt) > tolerance
+            {
+                self.late += 1;
+            }
+        }
+        if self.lowest.is_none_or(|l| seq_before(id.cnt, l)) {
+            self.lowest = Some(id.cnt);
+        }
+        self.interval_seqs = Some(match self.interval_seqs {
+            Some((low, high)) if seq_before(id.cnt, low) => (id.cnt, high),
+            Some((low, high)) if seq_before(high, id.cnt) => (low, id.cnt),
+            Some(seqs) => seqs,
+            None => (id.cnt, id.cnt),
+        });
+        // Late and duplicate frames are throughput but not goodput
+        let good = self.highest.is_none_or(|h| seq_before(h, id.cnt));
+        if good {
+            self.highest = Some(id.cnt);
+            self.good_bytes += len.saturating_sub(Id::size() as u64);
+        }
+        self.total_bytes += len;
+        let sample = Sample {
+            ts: Instant::now(),
+            seq: id.cnt,
+            bytes: len + self.pending_bytes,
+            frames: 1 + self.pending_frames,
+        };
+        if let Some(gaps) = &mut self.gaps {
+            gaps.record(sample.ts, len);
+        }
+        if let Some(fdv) = &mut self.fdv {
+            fdv.record(sample.ts, id.cnt, len);
+        }
+        if let Some(allan) = &mut self.allan {
+            allan.record(sample.ts);
+        }
+        // Past --max-samples new frames are folded into the last sample, which
+        // keeps the totals (and the summary) right but ends interval reports
+        if self.max_samples.is_some_and(|max| self.pkts.len() >= max) {
+            if !self.capped {
+                self.capped = true;
+                clear_progress();
+                eprintln!(
+                    "Stream {:x} reached {} samples, keeping totals only from now on",
+                    self.stream,
+                    self.pkts.len()
+                );
+            }
+            let last = self.pkts.last_mut().unwrap();
+            last.ts = sample.ts;
+            last.seq = sample.seq;
+            last.bytes += sample.bytes;
+            last.frames += sample.frames;
+        } else {
+            self.pkts.push(sample);
+        }
+        self.pending_bytes = 0;
+        self.pending_frames = 0;
+        good
+    }
+
+    // Count a frame that was not inspected because of --sample
+    fn skip(&mut self, len: u64) {
+        self.total_bytes += len;
+        self.good_bytes += len.saturating_sub(Id::size() as u64); // Can't tell, assume good
+        self.pending_bytes += len;
+        self.pending_frames += 1;
+    }
+
+    // How long this stream has to stay silent to count as restarted, see
+    // RESTART_SILENCE
+    fn restart_wait(&self) -> Duration {
+        let span = self
+            .pkts
+            .last()
+            .map_or(Duration::ZERO, |last| last.ts.duration_since(self.begin));
+        RESTART_SILENCE.max(4 * span / self.frames().max(1) as u32)
+    }
+
+    // Frames accounted for by the samples
+    fn frames(&self) -> u64 {
+        if self.pkts.is_empty() {
+            0
+        } else {
+            frames_in(&self.pkts)
+        }
+    }
+
+    fn report_rx(&mut self) -> Option<Report> {
+        let since_last = self.last_rep.elapsed().as_secs_f32();
+        if since_last >= 1.0 && !self.capped {
+            let since_begin = self.begin.elapsed().as_secs_f32();
+            let chunk = &self.pkts[self.last_ptr..];
+            let recv = frames_in(chunk);
+            // A handful of frames gives 0% or huge drop rates by chance, keep
+            // collecting and report a longer interval instead
+            if recv < self.min_interval_pkts {
+                return None;
+            }
+            let bytes: u64 = chunk.iter().map(|p| p.bytes).sum();
+
+            let cur_rate = ((8 * bytes) as f32) / since_last;
+
+            let (low, high) = self.interval_seqs.unwrap_or((chunk[0].seq, chunk[0].seq));
+            let id_diff = seq_span(low, high);
+            let dropped = id_diff.saturating_sub(recv);
+            let percent = (dropped as f32 / id_diff as f32) * 100.0;
+            self.interval_drops.push(percent);
+
+            let mut report = Report::new(Kind::Interval, Dir::Rx, self.stream);
+            report.start = since_begin - since_last;
+            report.end = since_begin;
+            report.pkts = recv;
+            report.expected = Some(id_diff);
+            report.dropped_pct = Some(percent);
+            report.rate_bps = cur_rate;
+            report.bytes = Some(bytes);
+            report.phase = self.phase;
+            if self.interval_goodput {
+                let good = self.good_bytes - self.rep_good_bytes;
+                report.goodput_bps = Some((8 * good) as f32 / since_last);
+            }
+
+            self.last_rep = Instant::now();
+            self.last_ptr = self.pkts.len() - 1;
+            self.rep_good_bytes = self.good_bytes;
+            // The next interval starts from the last sample, as its first
+            self.interval_seqs = Some((high, high));
+            return Some(report);
+        }
+        None
+    }
+
+    fn report_tx(&mut self) -> Option<Report> {
+        let since_last = self.last_rep.elapsed().as_secs_f32();
+        if since_last >= 1.0 && !self.capped {
+            let since_begin = self.begin.elapsed().as_secs_f32();
+            let chunk = &self.pkts[self.last_ptr..];
+            let bytes: u64 = chunk.iter().map(|p| p.bytes).sum();
+
+            let cur_rate = ((8 * bytes) as f32) / since_last;
+            self.peak_pps = self.peak_pps.max(chunk.len() as f32 / since_last);
+
+            let mut report = Report::new(Kind::Interval, Dir::Tx, self.stream);
+            report.start = since_begin - since_last;
+            report.end = since_begin;
+            report.pkts = chunk.len() as u64;
+            report.rate_bps = cur_rate;
+            report.bytes = Some(bytes);
+            report.phase = self.phase;
+
+            self.last_rep = Instant::now();
+            self.last_ptr = self.pkts.len() - 1;
+            return Some(report);
+        }
+        None
+    }
+
+    fn report_rx_summary(&self) -> Report {
+        let since_begin = self.begin.elapsed().as_secs_f32();
+        let since_end = self.pkts.last().unwrap().ts.elapsed().as_secs_f32();
+
+        let recv = frames_in(&self.pkts);
+        // From the lowest to the highest sequence number, so reordered frames
+        // at either end don't shrink the span
+        let (lowest, highest) = self
+            .lowest
+            .zip(self.highest)
+            .unwrap_or((self.pkts[0].seq, self.pkts[0].seq));
+        let id_diff = seq_span(lowest, highest);
+        // Frames later than the window are lost even though they did arrive
+        let unique = match self.reorder_window {
+            Some(_) => recv - self.duplicates - self.late,
+            None => recv - self.duplicates,
+        };
+        let dropped = id_diff.saturating_sub(unique);
+        let percent = (dropped as f32 / id_diff as f32) * 100.0;
+
+        let rate_tot = ((8 * self.total_bytes) as f32) / (since_begin - since_end);
+        let goodput = ((8 * self.good_bytes) as f32) / (since_begin - since_end);
+
+        let mut report = Report::new(Kind::Summary, Dir::Rx, self.stream);
+        report.end = since_begin - since_end;
+        report.pkts = recv;
+        report.expected = Some(id_diff);
+        report.dropped_pct = Some(percent);
+        report.rate_bps = rate_tot;
+        report.bytes = Some(self.total_bytes);
+        report.first_seq = self.pkts.first().map(|p| p.seq);
+        report.last_seq = self.pkts.last().map(|p| p.seq);
+        report.goodput_bps = Some(goodput);
+        report.latency = self.latency();
+        report.gaps = self.gaps.as_ref().and_then(GapHistogram::gaps);
+        report.fdv = self.fdv.as_ref().and_then(FdvClock::fdv);
+        report.allan = self.allan.as_ref().and_then(AllanDev::points);
+        report.bufferbloat = Bufferbloat::new(self.idle_latency, report.latency);
+        report.outage_secs = self.outage_secs();
+        report.paused_secs = self.paused_secs();
+        report.setup = self.setup;
+        report.phase = self.phase;
+        report.out_of_order = Some(self.out_of_order).filter(|&n| n > 0);
+        report.duplicates = Some(self.duplicates).filter(|&n| n > 0);
+        report.payload_dups = self.payloads.as_ref().map(|p| p.copies);
+        report.late = Some(self.late).filter(|&n| n > 0);
+        if let Some(log) = &self.drop_log {
+            report.lost = Some(log.ranges.clone());
+            report.lost_truncated = Some(log.truncated).filter(|&t| t);
+        }
+        report
+    }
+
+    // Mean time between the frames sent, see --ipg-ns
+    fn avg_ipg_ns(&self) -> Option<f64> {
+        let (first, last) = (self.pkts.first()?, self.pkts.last()?);
+        if self.frames() < 2 {
+            return None;
+        }
+        let span = last.ts.duration_since(first.ts).as_nanos() as f64;
+        Some(span / (self.frames() - 1) as f64)
+    }
+
+    // Per-VLAN breakdown, left out when every frame was untagged as it would
+    // just repeat the summary
+    fn vlan_reports(&self, secs: f32) -> Option<Vec<VlanReport>> {
+        let vlans = self.vlans.as_ref()?;
+        if vlans.keys().all(|vlan| vlan.is_none()) {
+            return None;
+        }
+        let reports = vlans
+            .iter()
+            .map(|(&vlan, stat)| {
+                let expected = seq_span(stat.lowest, stat.highest);
+                VlanReport {
+                    vlan,
+                    pkts: stat.pkts,
+                    expected,
+                    dropped_pct: expected.saturating_sub(stat.pkts) as f32 / expected as f32
+                        * 100.0,
+                    rate_bps: (8 * stat.bytes) as f32 / secs,
+                }
+            })
+            .collect();
+        Some(reports)
+    }
+
+    // Frames of one ethertype are `sweep.len()` sequence numbers apart, which
+    // gives the expected count from the range seen with it
+    fn ethertype_reports(&self, sweep: EthertypeSweep) -> Option<EthertypeSweepReport> {
+        let stats = self.ethertypes.as_ref()?;
+        let ethertypes = stats
+            .iter()
+            .map(|(&ethertype, stat)| {
+                let expected = stat.highest.wrapping_sub(stat.lowest) / sweep.len() + 1;
+                EthertypeReport {
+                    ethertype,
+                    pkts: stat.pkts,
+                    expected,
+                    dropped_pct: expected.saturating_sub(stat.pkts) as f32 / expected as f32
+                        * 100.0,
+                }
+            })
+            .collect();
+        Some(EthertypeSweepReport {
+            seen: stats.len() as u64,
+            total: sweep.len(),
+            ethertypes,
+        })
+    }
+
+    fn record_latency(&mut self, ts_ns: u64) -> f64 {
+        let delta = unix_ns() as i128 - ts_ns as i128;
+        let latency_us = delta as f64 / 1000.0;
+        self.latency_us.push(latency_us);
+        latency_us
+    }
+
+    fn latency(&self) -> Option<Latency> {
+        if self.latency_us.is_empty() {
+            return None;
+        }
+        let mut sorted = self.latency_us.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let pct = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        Some(Latency {
+            samples: sorted.len(),
+            min_us: sorted[0],
+            avg_us: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            max_us: sorted[sorted.len() - 1],
+            p50_us: pct(0.50),
+            p99_us: pct(0.99),
+        })
+    }
+
+    fn record_proc(&mut self, took: Duration) {
+        self.proc_total += took;
+        self.proc_max = self.proc_max.max(took);
+        self.proc_samples += 1;
+    }
+
+    fn rx_load(&self, secs: f32, pkts: u64) -> Option<RxLoad> {
+        if self.proc_samples == 0 || pkts == 0 {
+            return None;
+        }
+        Some(RxLoad {
+            avg_ns: self.proc_total.as_nanos() as f64 / self.proc_samples as f64,
+            max_ns: self.proc_max.as_nanos() as f64,
+            samples: self.proc_samples,
+            interarrival_ns: secs as f64 * 1e9 / pkts as f64,
+        })
+    }
+
+    // Move the clocks past a pause so that rates and intervals leave it out
+    fn resume(&mut self, paused: Duration) {
+        if let Some(gaps) = &mut self.gaps {
+            gaps.last = None;
+        }
+        if let Some(allan) = &mut self.allan {
+            allan.last = None;
+        }
+        let paused = paused.min(self.begin.elapsed());
+        if let Some(fdv) = &mut self.fdv {
+            fdv.shift(paused);
+        }
+        self.begin += paused;
+        self.last_rep += paused;
+        self.last_rolling += paused;
+        self.paused += paused;
+    }
+
+    fn paused_secs(&self) -> Option<f32> {
+        Some(self.paused.as_secs_f32()).filter(|&p| p > 0.0)
+    }
+
+    fn outage_secs(&self) -> Option<f32> {
+        if self.outage.is_zero() {
+            None
+        } else {
+            Some(self.outage.as_secs_f32())
+        }
+    }
+
+    fn interval_histogram(&self) -> [usize; 4] {
+        // Buckets: 0%, (0%, 1%), [1%, 5%], (5%, 100%]
+        let mut buckets = [0; 4];
+        for &p in &self.interval_drops {
+            let idx = if p == 0.0 {
+                0
+            } else if p < 1.0 {
+                1
+            } else if p <= 5.0 {
+                2
+            } else {
+                3
+            };
+            buckets[idx] += 1;
+        }
+        buckets
+    }
+
+    // (peak, average)
+    fn pps(&self) -> (f32, f32) {
+        let avg_pps = self.frames() as f32 / self.begin.elapsed().as_secs_f32();
+        (self.peak_pps.max(avg_pps), avg_pps)
+    }
+
+    fn report_tx_summary(&self) -> Report {
+        let since_begin = self.begin.elapsed().as_secs_f32();
+
+        let rate_tot = ((8 * self.total_bytes) as f32) / since_begin;
+
+        let mut report = Report::new(Kind::Summary, Dir::Tx, self.stream);
+        report.end = since_begin;
+        report.pkts = self.frames();
+        report.rate_bps = rate_tot;
+        report.bytes = Some(self.total_bytes);
+        report.outage_secs = self.outage_secs();
+        report.paused_secs = self.paused_secs();
+        report.phase = self.phase;
+        report.setup = self.setup;
+        report
+    }
+}
+
+// Parts of the per-frame work timed when built with the hotpath-timing
+// feature. Without it `hot` just runs the closure.
+#[derive(Debug, Clone, Copy)]
+enum Hot {
+    Serialize, // Encoding the Id on TX, parsing the frame on RX
+    Send,
+    Tracker,
+    Report,
+}
+
+const HOT_NAMES: [&str; 4] = ["serialization", "send", "tracker", "reporting"];
+
+#[cfg(feature = "hotpath-timing")]
+static HOT_NS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[inline(always)]
+fn hot<T>(part: Hot, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "hotpath-timing")]
+    {
+        let start = Instant::now();
+        let out = f();
+        HOT_NS[part as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        out
+    }
+    #[cfg(not(feature = "hotpath-timing"))]
+    {
+        let _ = part;
+        f()
+    }
+}
+
+// The time in each part so far as a share of all timed time, summed over
+// the threads. Printed after each summary, so the last one covers the run.
+fn report_hotpath() {
+    #[cfg(feature = "hotpath-timing")]
+    {
+        let ns: Vec<u64> = HOT_NS.iter().map(|n| n.load(Ordering::Relaxed)).collect();
+        let total = ns.iter().sum::<u64>().max(1);
+        let parts: Vec<String> = HOT_NAMES
+            .iter()
+            .zip(&ns)
+            .map(|(name, &ns)| {
+                format!(
+                    "{} {:.1}% ({:.1} ms)",
+                    name,
+                    ns as f64 * 100.0 / total as f64,
+                    ns as f64 / 1e6
+                )
+            })
+            .collect();
+        clear_progress();
+        eprintln!("Hot path: {}", parts.join(", "));
+    }
+    #[cfg(not(feature = "hotpath-timing"))]
+    let _ = HOT_NAMES;
+}
+
+// Interval reports go to stdout, or with --progress to a status line on
+// stderr that is redrawn in place (plain lines if stderr is not a terminal)
+fn report_interval(opts: &Opt, mut report: Report) {
+    hot(Hot::Report, || {
+        report.unit = opts.units.pick(report.rate_bps);
+        export_report(&report);
+        if matches!(report.kind, Kind::Interval) {
+            plot_interval(&report);
+        }
+        if opts.iperf3_json {
+            iperf3_record(&report);
+        }
+        send_result(&report);
+        if opts.control.is_some() && report.dir == Dir::Rx {
+            control_interval(&report);
+        }
+        if !opts.progress {
+            print_report(opts, &report);
+        } else if io::stderr().is_terminal() {
+            eprint!("\r\x1b[K{}", labeled(report.to_string()));
+            PROGRESS_LINE.store(true, Ordering::SeqCst);
+        } else {
+            eprintln!("{}", labeled(report.to_string()));
+        }
+        request_sync();
+    })
+}
+
+// Prints and exports a summary, returning it with the fields only known here
+// (unit, utilization) filled in
+fn report_summary(opts: &Opt, report: Report) -> Report {
+    hot(Hot::Report, || summarize(opts, report))
+}
+
+fn summarize(opts: &Opt, mut report: Report) -> Report {
+    report.unit = opts.units.pick(report.rate_bps);
+    if let Some(mbps) = opts.link_speed {
+        report.utilization = Utilization::new(&report, mbps * 1_000_000.0);
+        if report.utilization.is_some_and(|u| u.wire_pct > 100.0) {
+            clear_progress();
+            eprintln!(
+                "Warning: stream {:x} exceeds the --link-speed of {} Mbps, check the value",
+                report.stream, mbps
+            );
+        }
+    }
+    export_report(&report);
+    send_result(&report);
+    if opts.iperf3_json {
+        iperf3_record(&report);
+    }
+    clear_progress();
+    if opts.oneline {
+        println!("{}", oneline(&report));
+    } else {
+        print_report(opts, &report);
+    }
+    // Summaries are rare and often the last thing before exit, sync them here
+    if opts.durable {
+        sync_outputs();
+    }
+    report
+}
+
+// --oneline: a fixed set of space separated key=value pairs, for awk and grep.
+// Rates in bps and times in us, unrounded; keys without a value are left out
+// like the columns of the text report.
+fn oneline(report: &Report) -> String {
+    let mut out = vec![];
+    if let Some(label) = LABEL.get() {
+        out.push(format!("label={}", label));
+    }
+    let kind = match report.kind {
+        Kind::Interval => "interval",
+        Kind::Summary => "summary",
+        Kind::Rolling => "rolling",
+    };
+    let dir = match report.dir {
+        Dir::Tx => "tx",
+        Dir::Rx => "rx",
+    };
+    out.push(format!("type={} dir={}", kind, dir));
+    match report.phase {
+        Some(Phase::Probe) => out.push("phase=probe".to_string()),
+        Some(Phase::Sustained) => out.push("phase=sustained".to_string()),
+        None => {}
+    }
+    out.push(format!("stream={:x}", report.stream));
+    out.push(format!("secs={}", report.end - report.start));
+    out.push(format!("pkts={}", report.pkts));
+    if let Some(expected) = report.expected {
+        out.push(format!("expected={}", expected));
+    }
+    if let Some(pct) = report.dropped_pct {
+        out.push(format!("drops={}", pct));
+    }
+    out.push(format!("rate={}", report.rate_bps));
+    if let Some(goodput) = report.goodput_bps {
+        out.push(format!("goodput={}", goodput));
+    }
+    let secs = report.end - report.start;
+    if secs > 0.0 {
+        out.push(format!("pps={}", report.pkts as f32 / secs));
+    }
+    // As the jitter column: the mean delay variation with --fdv-rate,
+    // otherwise the p99 gap between frames
+    let jitter = report
+        .fdv
+        .map(|d| d.mean_us)
+        .or_else(|| report.gaps.map(|g| g.p99_us));
+    if let Some(jitter) = jitter {
+        out.push(format!("jitter={}", jitter));
+    }
+    if let Some(latency) = report.latency {
+        out.push(format!("latency={}", latency.p50_us));
+    }
+    out.join(" ")
+}
+
+// Set from --label
+static LABEL: OnceLock<String> = OnceLock::new();
+
+// A text report with each line led by the --label
+fn labeled(text: String) -> String {
+    match LABEL.get() {
+        Some(label) => text
+            .lines()
+            .map(|line| format!("[{}] {}", label, line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => text,
+    }
+}
+
+// A report as a JSON object, with the --label as its first field
+fn to_json<T: Serialize>(report: &T) -> String {
+    let json = serde_json::to_string(report).unwrap();
+    match LABEL.get() {
+        Some(label) if json.starts_with('{') => {
+            let label = serde_json::to_string(label).unwrap();
+            format!("{{\"label\":{},{}", label, &json[1..])
+        }
+        _ => json,
+    }
+}
+
+fn print_report<T: Serialize + fmt::Display>(opts: &Opt, report: &T) {
+    if opts.iperf3_json {
+        // Stream reports are collected for the document printed at the end,
+        // others have no place in it
+    } else if opts.json {
+        println!("{}", to_json(report));
+    } else {
+        println!("{}", labeled(report.to_string()));
+    }
+}
+
+// What --iperf3-json keeps of a stream report
+#[derive(Debug, Clone, Copy)]
+struct Iperf3Sample {
+    kind: Kind,
+    dir: Dir,
+    stream: u32,
+    start: f32,
+    end: f32,
+    pkts: u64,
+    expected: Option<u64>,
+    rate_bps: f32,
+    jitter_ms: Option<f64>,
+    out_of_order: Option<u64>,
+}
+
+static IPERF3: Mutex<Vec<Iperf3Sample>> = Mutex::new(Vec::new());
+
+fn iperf3_record(report: &Report) {
+    if let Kind::Rolling = report.kind {
+        return;
+    }
+    IPERF3.lock().unwrap().push(Iperf3Sample {
+        kind: report.kind,
+        dir: report.dir,
+        stream: report.stream,
+        start: report.start,
+        end: report.end,
+        pkts: report.pkts,
+        expected: report.expected,
+        rate_bps: report.rate_bps,
+        jitter_ms: report.fdv.map(|d| d.mean_us / 1e3),
+        out_of_order: report.out_of_order,
+    });
+}
+
+impl Iperf3Sample {
+    // The fields of an iperf3 UDP stream entry. A receiver's `packets` are
+    // the frames the sender sent, as in iperf3; bytes follow from the rate.
+    fn fields(&self, socket: Option<usize>) -> serde_json::Value {
+        let seconds = self.end - self.start;
+        let packets = self.expected.unwrap_or(self.pkts);
+        let lost = packets.saturating_sub(self.pkts);
+        let mut fields = serde_json::Map::new();
+        if let Some(socket) = socket {
+            fields.insert("socket".to_string(), socket.into());
+        }
+        let rest = serde_json::json!({
+            "start": self.start,
+            "end": self.end,
+            "seconds": seconds,
+            "bytes": (self.rate_bps as f64 * seconds as f64 / 8.0).round() as u64,
+            "bits_per_second": self.rate_bps,
+            "jitter_ms": self.jitter_ms.unwrap_or(0.0),
+            "lost_packets": lost,
+            "packets": packets,
+            "lost_percent": if packets > 0 { lost as f64 * 100.0 / packets as f64 } else { 0.0 },
+            "out_of_order": self.out_of_order.unwrap_or(0),
+            "omitted": false,
+            "sender": matches!(self.dir, Dir::Tx),
+        });
+        fields.extend(rest.as_object().unwrap().clone());
+        fields.into()
+    }
+}
+
+// Sum entry over the streams of one interval or of the whole test
+fn iperf3_sum(samples: &[Iperf3Sample]) -> serde_json::Value {
+    let mut sum = Iperf3Sample {
+        kind: Kind::Summary,
+        dir: samples.first().map_or(Dir::Rx, |s| s.dir),
+        stream: 0,
+        start: samples.iter().map(|s| s.start).fold(f32::MAX, f32::min),
+        end: samples.iter().map(|s| s.end).fold(0.0, f32::max),
+        pkts: samples.iter().map(|s| s.pkts).sum(),
+        expected: Some(samples.iter().map(|s| s.expected.unwrap_or(s.pkts)).sum()),
+        rate_bps: samples.iter().map(|s| s.rate_bps).sum(),
+        jitter_ms: None,
+        out_of_order: Some(samples.iter().filter_map(|s| s.out_of_order).sum()),
+    };
+    if samples.is_empty() {
+        sum.start = 0.0;
+    }
+    let jitters: Vec<f64> = samples.iter().filter_map(|s| s.jitter_ms).collect();
+    if !jitters.is_empty() {
+        sum.jitter_ms = Some(jitters.iter().sum::<f64>() / jitters.len() as f64);
+    }
+    sum.fields(None)
+}
+
+// --iperf3-json: the collected reports as an iperf3 result document. The
+// intervals and streams are the receiver's if this side received, like an
+// iperf3 UDP server, otherwise the sender's. Sockets number the streams
+// from 1 in the order they were first reported.
+fn print_iperf3(opts: &Opt) {
+    let samples = IPERF3.lock().unwrap().clone();
+    let dir = if samples.iter().any(|s| matches!(s.dir, Dir::Rx)) {
+        Dir::Rx
+    } else {
+        Dir::Tx
+    };
+    let ours: Vec<Iperf3Sample> = samples.iter().copied().filter(|s| s.dir == dir).collect();
+    let mut streams: Vec<u32> = vec![];
+    for s in &ours {
+        if !streams.contains(&s.stream) {
+            streams.push(s.stream);
+        }
+    }
+    let socket = |stream: u32| streams.iter().position(|&s| s == stream).unwrap() + 1;
+
+    // The nth interval of every stream makes the nth iperf3 interval
+    let mut intervals: Vec<Vec<Iperf3Sample>> = vec![];
+    let mut seen: HashMap<u32, usize> = HashMap::new();
+    for s in ours.iter().filter(|s| matches!(s.kind, Kind::Interval)) {
+        let n = seen.entry(s.stream).or_insert(0);
+        if intervals.len() <= *n {
+            intervals.push(vec![]);
+        }
+        intervals[*n].push(*s);
+        *n += 1;
+    }
+    let intervals: Vec<serde_json::Value> = intervals
+        .iter()
+        .map(|samples| {
+            serde_json::json!({
+                "streams": samples.iter().map(|s| s.fields(Some(socket(s.stream)))).collect::<Vec<_>>(),
+                "sum": iperf3_sum(samples),
+            })
+        })
+        .collect();
+
+    let summaries = |dir: Dir| -> Vec<Iperf3Sample> {
+        samples
+            .iter()
+            .copied()
+            .filter(|s| matches!(s.kind, Kind::Summary) && s.dir == dir)
+            .collect()
+    };
+    let ends = summaries(dir);
+    let mut end = serde_json::json!({
+        "streams": ends
+            .iter()
+            .map(|s| serde_json::json!({ "udp": s.fields(Some(socket(s.stream))) }))
+            .collect::<Vec<_>>(),
+        "sum": iperf3_sum(&ends),
+    });
+    for (key, dir) in [("sum_sent", Dir::Tx), ("sum_received", Dir::Rx)] {
+        let sums = summaries(dir);
+        if !sums.is_empty() {
+            end[key] = iperf3_sum(&sums);
+        }
+    }
+
+    let timesecs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        .saturating_sub(since_start() as u64);
+    let mut doc = serde_json::json!({
+        "start": {
+            "version": format!("l2perf {}", env!("CARGO_PKG_VERSION")),
+            "timestamp": { "timesecs": timesecs },
+            "connecting_to": {
+                "host": opts
+                    .dest
+                    .map(|mac| mac.to_string())
+                    .or_else(|| opts.rx_ifname.clone())
+                    .unwrap_or_default(),
+                "port": 0,
+            },
+            "test_start": {
+                "protocol": "ETH",
+                "num_streams": streams.len(),
+                "blksize": opts.psize,
+                "omit": 0,
+                "duration": opts.tsecs,
+                "bytes": 0,
+                "blocks": 0,
+                "reverse": 0,
+                "target_bitrate": (opts.bandwidth as f64 * 1e6) as u64,
+            },
+        },
+        "intervals": intervals,
+        "end": end,
+    });
+    // Where iperf3 keeps its --title
+    if let Some(label) = LABEL.get() {
+        doc["start"]["title"] = label.as_str().into();
+    }
+    println!("{}", serde_json::to_string_pretty(&doc).unwrap());
+}
+
+// --export file layout: EXPORT_MAGIC, then the version as a little-endian
+// u16, then one ExportRecord per report, encoded as in `wire`. Bump
+// EXPORT_VERSION on any change to ExportRecord.
+const EXPORT_MAGIC: &[u8; 4] = b"L2PX";
+const EXPORT_VERSION: u16 = 1;
+
+// Open --export file, shared by the TX and RX threads in loopback mode
+static EXPORT: Mutex<Option<File>> = Mutex::new(None);
+
+// --result-socket connection, shared like EXPORT
+#[cfg(unix)]
+static RESULT_SOCKET: Mutex<Option<ResultSocket>> = Mutex::new(None);
+
+// A stuck reader holds up a report at most this long before it's dropped
+#[cfg(unix)]
+const RESULT_TIMEOUT: Duration = Duration::from_millis(100);
+// Wait between attempts to connect, reports in between are dropped
+#[cfg(unix)]
+const RESULT_RETRY: Duration = Duration::from_secs(1);
+
+// Wakes the --durable sync thread, set only with --durable
+static SYNC: OnceLock<mpsc::SyncSender<()>> = OnceLock::new();
+
+// Fixed layout of an exported report, bincode can't skip fields like the
+// JSON output does
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportRecord {
+    kind: Kind,
+    dir: Dir,
+    stream: u32,
+    start: f32,
+    end: f32,
+    pkts: u64,
+    expected: Option<u64>,
+    dropped_pct: Option<f32>,
+    rate_bps: f32,
+    goodput_bps: Option<f32>,
+    outage_secs: Option<f32>,
+    phase: Option<Phase>,
+}
+
+impl ExportRecord {
+    fn new(report: &Report) -> Self {
+        Self {
+            kind: report.kind,
+            dir: report.dir,
+            stream: report.stream,
+            start: report.start,
+            end: report.end,
+            pkts: report.pkts,
+            expected: report.expected,
+            dropped_pct: report.dropped_pct,
+            rate_bps: report.rate_bps,
+            goodput_bps: report.goodput_bps,
+            outage_secs: report.outage_secs,
+            phase: report.phase,
+        }
+    }
+
+    // Without the unit, which the reader picks
+    fn report(&self) -> Report {
+        let mut report = Report::new(self.kind, self.dir, self.stream);
+        report.start = self.start;
+        report.end = self.end;
+        report.pkts = self.pkts;
+        report.expected = self.expected;
+        report.dropped_pct = self.dropped_pct;
+        report.rate_bps = self.rate_bps;
+        report.goodput_bps = self.goodput_bps;
+        report.outage_secs = self.outage_secs;
+        report.phase = self.phase;
+        report
+    }
+}
+
+// Upper bounds of the --metrics-port latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [
+    10e-6, 50e-6, 100e-6, 500e-6, 1e-3, 5e-3, 10e-3, 50e-3, 100e-3,
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Exemplar {
+    stream: u32,
+    seq: Seq,
+    secs: f64,
+    unix_secs: f64, // When it was observed
+}
+
+// RX counters for --metrics-port, kept for finished streams too as
+// Prometheus counters must not go backwards
+#[derive(Debug)]
+struct Metrics {
+    streams: BTreeMap<u32, (u64, u64)>, // Frames and bytes by stream id
+    buckets: [u64; LATENCY_BUCKETS.len() + 1], // Not cumulative, the last is +Inf
+    exemplars: [Option<Exemplar>; LATENCY_BUCKETS.len() + 1],
+    latency_sum: f64,
+    with_exemplars: bool,
+}
+
+// Set with --metrics-port
+static METRICS: Mutex<Option<Metrics>> = Mutex::new(None);
+
+impl Metrics {
+    fn record(&mut self, id: &Id, len: u64, latency_us: Option<f64>) {
+        let (frames, bytes) = self.streams.entry(id.id).or_default();
+        *frames += 1;
+        *bytes += len;
+
+        // Clock skew can make one-way latency negative, which a histogram
+        // with a _sum can't hold
+        if let Some(secs) = latency_us.map(|us| (us / 1e6).max(0.0)) {
+            let idx = LATENCY_BUCKETS
+                .iter()
+                .position(|&le| secs <= le)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            self.buckets[idx] += 1;
+            self.latency_sum += secs;
+            if self.with_exemplars {
+                self.exemplars[idx] = Some(Exemplar {
+                    stream: id.id,
+                    seq: id.cnt,
+                    secs,
+                    unix_secs: unix_ns() as f64 / 1e9,
+                });
+            }
+        }
+    }
+
+    // OpenMetrics text exposition
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE l2perf_rx_frames counter\n");
+        out.push_str("# HELP l2perf_rx_frames Frames received by stream.\n");
+        for (stream, (frames, _)) in &self.streams {
+            out.push_str(&format!(
+                "l2perf_rx_frames_total{{stream=\"{:x}\"}} {}\n",
+                stream, frames
+            ));
+        }
+        out.push_str("# TYPE l2perf_rx_bytes counter\n");
+        out.push_str("# UNIT l2perf_rx_bytes bytes\n");
+        out.push_str("# HELP l2perf_rx_bytes Payload bytes received by stream.\n");
+        for (stream, (_, bytes)) in &self.streams {
+            out.push_str(&format!(
+                "l2perf_rx_bytes_total{{stream=\"{:x}\"}} {}\n",
+                stream, bytes
+            ));
+        }
+
+        out.push_str("# TYPE l2perf_rx_latency_seconds histogram\n");
+        out.push_str("# UNIT l2perf_rx_latency_seconds seconds\n");
+        out.push_str("# HELP l2perf_rx_latency_seconds One-way latency of timestamped frames.\n");
+        let mut count = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            count += n;
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |le| le.to_string());
+            out.push_str(&format!(
+                "l2perf_rx_latency_seconds_bucket{{le=\"{}\"}} {}",
+                le, count
+            ));
+            if let Some(e) = self.exemplars[i] {
+                out.push_str(&format!(
+                    " # {{stream=\"{:x}\",seq=\"{}\"}} {} {:.3}",
+                    e.stream, e.seq, e.secs, e.unix_secs
+                ));
+            }
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "l2perf_rx_latency_seconds_sum {}\n",
+            self.latency_sum
+        ));
+        out.push_str(&format!("l2perf_rx_latency_seconds_count {}\n", count));
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+// Answer every request on `port` with the current metrics. Prometheus asks
+// for OpenMetrics first, and only that format carries exemplars.
+fn serve_metrics(opts: &Opt, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    *METRICS.lock().unwrap() = Some(Metrics {
+        streams: BTreeMap::new(),
+        buckets: [0; LATENCY_BUCKETS.len() + 1],
+        exemplars: [None; LATENCY_BUCKETS.len() + 1],
+        latency_sum: 0.0,
+        with_exemplars: opts.metrics_exemplars,
+    });
+    report_info(opts, &format!("Metrics on port {}", port));
+
+    thread::spawn(move || {
+        for mut conn in listener.incoming().flatten() {
+            // Whatever was asked, the answer is the same
+            conn.set_read_timeout(Some(Duration::from_secs(1))).ok();
+            let mut request = [0; 4096];
+            let _ = conn.read(&mut request);
+
+            let body = METRICS.lock().unwrap().as_ref().unwrap().encode();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = conn.write_all(response.as_bytes());
+        }
+    });
+    Ok(())
+}
+
+// Columns of --receive-only-stats, empty cells for values a summary lacks
+const STATS_HEADER: &str = "unix_time,host,interface,stream,phase,start,end,pkts,expected,\
+dropped_pct,rate_bps,goodput_bps,outage_secs,wrong_ethertype,latency_p50_us,latency_p99_us,label";
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// Quote a CSV field if it needs it (RFC 4180)
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Tries before --output-dir gives up on finding a free file name
+const MAX_OUTPUT_NAMES: usize = 100;
+
+// --output-dir: the summary as printed (text or JSON) and its --receive-only-stats
+// style CSV row, in files named by stream id, sender MAC if known, phase and
+// time. An existing file is never overwritten, a numbered name is used instead.
+fn write_output_files(
+    dir: &Path,
+    opts: &Opt,
+    report: &Report,
+    src: Option<MacAddr>,
+) -> io::Result<PathBuf> {
+    let mut base = format!("{:08x}", report.stream);
+    if let Some(mac) = src {
+        base += &format!("-{}", mac.to_string().replace(':', ""));
+    }
+    if let Some(phase) = report.phase {
+        base += &format!("-{}", format!("{:?}", phase).to_lowercase());
+    }
+    base += &format!("-{}", unix_ns() / 1_000_000_000);
+    let ext = if opts.json { "json" } else { "txt" };
+
+    for n in 0..MAX_OUTPUT_NAMES {
+        let name = match n {
+            0 => base.clone(),
+            n => format!("{}-{}", base, n),
+        };
+        let path = dir.join(format!("{}.{}", name, ext));
+        let csv = dir.join(format!("{}.csv", name));
+        if csv.exists() {
+            continue;
+        }
+        let mut file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            file => file?,
+        };
+        if opts.json {
+            writeln!(file, "{}", to_json(report))?;
+        } else {
+            writeln!(file, "{}", labeled(report.to_string()))?;
+        }
+        if opts.durable {
+            file.sync_data()?;
+        }
+        append_stats_row(&csv, opts, report)?;
+        return Ok(path);
+    }
+    Err(io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!(
+            "no free file name for {} after {} tries",
+            base, MAX_OUTPUT_NAMES
+        ),
+    ))
+}
+
+// Append one row to a CSV file shared by many receivers. An exclusive flock
+// serializes the writers, and whoever finds the file empty adds the header.
+fn append_stats_row(path: &Path, opts: &Opt, report: &Report) -> io::Result<()> {
+    let cell = |v: Option<String>| v.unwrap_or_default();
+    let phase = report.phase.map(|p| format!("{:?}", p).to_lowercase());
+    let row = [
+        (unix_ns() / 1_000_000_000).to_string(),
+        csv_field(&hostname()),
+        csv_field(&opts.ifname),
+        format!("{:x}", report.stream),
+        cell(phase),
+        report.start.to_string(),
+        report.end.to_string(),
+        report.pkts.to_string(),
+        cell(report.expected.map(|v| v.to_string())),
+        cell(report.dropped_pct.map(|v| v.to_string())),
+        report.rate_bps.to_string(),
+        cell(report.goodput_bps.map(|v| v.to_string())),
+        cell(report.outage_secs.map(|v| v.to_string())),
+        cell(report.wrong_ethertype.map(|v| v.to_string())),
+        cell(report.latency.map(|l| l.p50_us.to_string())),
+        cell(report.latency.map(|l| l.p99_us.to_string())),
+        cell(LABEL.get().map(|l| csv_field(l))),
+    ]
+    .join(",");
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    lock_exclusive(&file)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", STATS_HEADER)?;
+    }
+    // One write per row so a reader never sees half of it
+    file.write_all(format!("{}\n", row).as_bytes())?;
+    if opts.durable {
+        file.sync_data()?;
+    }
+    // The lock goes away with the file
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+fn open_export(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(EXPORT_MAGIC)?;
+    file.write_all(&EXPORT_VERSION.to_le_bytes())?;
+    *EXPORT.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+// Open --plot-data file, and the side whose intervals go into it
+static PLOT: Mutex<Option<(File, Dir)>> = Mutex::new(None);
+
+// The receiver's intervals when this process receives, they have the drops
+fn open_plot_data(opts: &Opt, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let dir = if opts.rx || opts.rx_ifname.is_some() {
+        writeln!(file, "# elapsed_seconds,rate_mbps,drop_pct")?;
+        Dir::Rx
+    } else {
+        writeln!(file, "# elapsed_seconds,rate_mbps")?;
+        Dir::Tx
+    };
+    *PLOT.lock().unwrap() = Some((file, dir));
+    if opts.plot_script {
+        write_plot_script(path, dir)?;
+    }
+    Ok(())
+}
+
+fn write_plot_script(data: &Path, dir: Dir) -> io::Result<()> {
+    let mut path = data.as_os_str().to_owned();
+    path.push(".gp");
+    let mut script = File::create(&path)?;
+    // Relative to the script's directory, where gnuplot is expected to run
+    let name = data
+        .file_name()
+        .unwrap_or(data.as_os_str())
+        .to_string_lossy();
+    writeln!(script, "set datafile separator \",\"")?;
+    writeln!(script, "set xlabel \"Elapsed (s)\"")?;
+    writeln!(script, "set ylabel \"Rate (Mbps)\"")?;
+    writeln!(script, "set grid")?;
+    match dir {
+        Dir::Tx => writeln!(
+            script,
+            "plot \"{}\" using 1:2 with lines title \"Rate\"",
+            name
+        ),
+        Dir::Rx => {
+            writeln!(script, "set y2label \"Dropped (%)\"")?;
+            writeln!(script, "set ytics nomirror")?;
+            writeln!(script, "set y2tics")?;
+            writeln!(
+                script,
+                "plot \"{}\" using 1:2 with lines title \"Rate\", \"\" using 1:3 axes x1y2 with lines title \"Dropped\"",
+                name
+            )
+        }
+    }
+}
+
+fn plot_interval(report: &Report) {
+    let mut plot = PLOT.lock().unwrap();
+    let file = match plot.as_mut() {
+        Some((file, dir)) if *dir == report.dir => file,
+        _ => return,
+    };
+    let mbps = report.rate_bps / 1_000_000.0;
+    let written = match report.dropped_pct {
+        Some(pct) => writeln!(file, "{},{},{}", report.end, mbps, pct),
+        None => writeln!(file, "{},{}", report.end, mbps),
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write the plot data, stopping: {}", e);
+        *plot = None;
+    }
+}
+
+// Records are written as they're reported so nothing piles up in memory
+fn export_report(report: &Report) {
+    let mut export = EXPORT.lock().unwrap();
+    let file = match export.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+    let buf = wire().serialize(&ExportRecord::new(report)).unwrap();
+    if let Err(e) = file.write_all(&buf) {
+        eprintln!("Failed to write the export file, stopping export: {}", e);
+        *export = None;
+    }
+}
+
+// Reports go out as they're made, to a reader that may come and go. While the
+// socket isn't there reports are dropped, with a warning when it goes away and
+// the number lost once it's back.
+#[cfg(unix)]
+struct ResultSocket {
+    path: PathBuf,
+    stream: Option<std::os::unix::net::UnixStream>,
+    last_attempt: Option<Instant>,
+    warned: bool,
+    dropped: u64,
+}
+
+#[cfg(unix)]
+impl ResultSocket {
+    fn send(&mut self, line: &str) {
+        if self.stream.is_none() && !self.connect() {
+            self.dropped += 1;
+            return;
+        }
+        let stream = self.stream.as_mut().unwrap();
+        if let Err(e) = stream.write_all(format!("{}\n", line).as_bytes()) {
+            clear_progress();
+            eprintln!(
+                "Lost the result socket {}: {}, dropping reports until it's back",
+                self.path.display(),
+                e
+            );
+            self.stream = None;
+            self.warned = true;
+            self.dropped += 1;
+        }
+    }
+
+    fn connect(&mut self) -> bool {
+        if self
+            .last_attempt
+            .is_some_and(|t| t.elapsed() < RESULT_RETRY)
+        {
+            return false;
+        }
+        self.last_attempt = Some(Instant::now());
+        let stream = match std::os::unix::net::UnixStream::connect(&self.path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                if !self.warned {
+                    clear_progress();
+                    eprintln!(
+                        "Result socket {}: {}, dropping reports until it's available",
+                        self.path.display(),
+                        e
+                    );
+                    self.warned = true;
+                }
+                return false;
+            }
+        };
+        stream.set_write_timeout(Some(RESULT_TIMEOUT)).ok();
+        if self.dropped > 0 {
+            clear_progress();
+            eprintln!(
+                "Connected to the result socket {}, {} reports were dropped",
+                self.path.display(),
+                self.dropped
+            );
+        }
+        self.stream = Some(stream);
+        self.warned = false;
+        self.dropped = 0;
+        true
+    }
+}
+
+#[cfg(unix)]
+fn open_result_socket(path: &Path) {
+    let mut socket = ResultSocket {
+        path: path.to_path_buf(),
+        stream: None,
+        last_attempt: None,
+        warned: false,
+        dropped: 0,
+    };
+    socket.connect();
+    *RESULT_SOCKET.lock().unwrap() = Some(socket);
+}
+
+#[cfg(not(unix))]
+fn open_result_socket(_path: &Path) {}
+
+#[cfg(unix)]
+fn send_result(report: &Report) {
+    if let Some(socket) = RESULT_SOCKET.lock().unwrap().as_mut() {
+        socket.send(&to_json(report));
+    }
+}
+
+#[cfg(not(unix))]
+fn send_result(_report: &Report) {}
+
+// --durable: interval reports only poke a thread that does the fsyncs, which
+// can take milliseconds on a busy disk and would stall the TX/RX loops
+fn start_sync_thread() {
+    // A single slot, reports that come in while a sync runs share the next one
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        for () in rx {
+            sync_outputs();
+        }
+    });
+    SYNC.set(tx).ok();
+}
+
+fn request_sync() {
+    if let Some(tx) = SYNC.get() {
+        tx.try_send(()).ok();
+    }
+}
+
+// Push everything written so far to the disk. stdout is only synced when it
+// is redirected to a file, pipes and terminals have nothing to persist.
+fn sync_outputs() {
+    io::stdout().flush().ok();
+    if let Err(e) = sync_stdout() {
+        eprintln!("Failed to sync stdout: {}", e);
+    }
+    // Sync a duplicate so reports aren't held up behind the lock meanwhile
+    let export = EXPORT.lock().unwrap().as_ref().map(File::try_clone);
+    if let Some(Err(e)) = export.map(|f| f.and_then(|f| f.sync_data())) {
+        eprintln!("Failed to sync the export file: {}", e);
+    }
+}
+
+#[cfg(unix)]
+fn sync_stdout() -> io::Result<()> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(libc::STDOUT_FILENO, &mut st) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return Ok(());
+    }
+    if unsafe { libc::fsync(libc::STDOUT_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_stdout() -> io::Result<()> {
+    Ok(())
+}
+
+// Re-print the summaries of an --export file
+fn import_results(opts: &Opt, path: &Path) -> Result<(), Error> {
+    let err = |msg: String| Error::Import {
+        path: path.to_path_buf(),
+        msg,
+    };
+    let data = std::fs::read(path).map_err(|e| err(e.to_string()))?;
+    if data.len() < 6 || &data[..4] != EXPORT_MAGIC {
+        return Err(err("not an l2perf export file".to_string()));
+    }
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != EXPORT_VERSION {
+        return Err(err(format!(
+            "unsupported export version {} (expected {})",
+            version, EXPORT_VERSION
+        )));
+    }
+
+    let mut rest = &data[6..];
+    while !rest.is_empty() {
+        let record: ExportRecord = wire()
+            .deserialize_from(&mut rest)
+            .map_err(|e| err(format!("truncated or corrupt record: {}", e)))?;
+        if let Kind::Interval = record.kind {
+            continue;
+        }
+        let mut report = record.report();
+        report.unit = opts.units.pick(report.rate_bps);
+        print_report(opts, &report);
+    }
+    Ok(())
+}
+
+// Erase the --progress status line so other output starts on a clean row
+fn clear_progress() {
+    if PROGRESS_LINE.swap(false, Ordering::SeqCst) {
+        eprint!("\r\x1b[K");
+    }
+}
+
+// Informational banners, kept off stdout in --progress and --json modes
+fn report_info(opts: &Opt, msg: &str) {
+    if opts.progress || opts.json || opts.iperf3_json {
+        clear_progress();
+        eprintln!("{}", msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+// Send a frame, riding out a link outage of up to `grace` by retrying with
+// backoff. Time spent waiting for the link is added to `outage`.
+fn send_frame(
+    tx: &mut Box<dyn datalink::DataLinkSender>,
+    frame: &[u8],
+    grace: Duration,
+    outage: &mut Duration,
+) -> io::Result<()> {
+    let mut down_since: Option<Instant> = None;
+    let mut backoff = Duration::from_millis(10);
+
+    loop {
+        match tx.send_to(frame, None).unwrap() {
+            Ok(()) => {
+                if let Some(since) = down_since {
+                    *outage += since.elapsed();
+                    eprintln!("Link up after {:.2}s", since.elapsed().as_secs_f32());
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::NetworkDown && grace.is_zero() => return Err(e),
+            Err(e) if e.kind() == ErrorKind::NetworkDown => {
+                let since = *down_since.get_or_insert_with(|| {
+                    clear_progress();
+                    eprintln!("Link down, waiting up to {}s...", grace.as_secs());
+                    Instant::now()
+                });
+                if since.elapsed() >= grace {
+                    *outage += since.elapsed();
+                    return Err(e);
+                }
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Filler bytes for --entropy: each byte is random with probability `entropy`
+// and zero otherwise. Frames copy a window at a random offset of this pool, so
+// their contents vary without generating random bytes per frame.
+fn entropy_pool(rng: &mut impl Rng, entropy: f64, len: usize) -> Vec<u8> {
+    if entropy == 0.0 {
+        return vec![];
+    }
+    (0..len)
+        .map(|_| if rng.gen_bool(entropy) { rng.gen() } else { 0 })
+        .collect()
+}
+
+// Send times and payload sizes of --schedule, loaded before the test starts
+#[derive(Debug)]
+struct Schedule {
+    frames: Vec<(Duration, usize)>,
+    lap: Duration, // With --schedule-loop, between the starts of two rounds
+}
+
+static SCHEDULE: OnceLock<Schedule> = OnceLock::new();
+
+// Lines of "OFFSET [SIZE]", the offset in seconds and the payload size in
+// bytes (--psize without one), separated by spaces or a comma. Blank lines and
+// ones starting with # are skipped.
+fn load_schedule(path: &Path, psize: usize) -> Result<Schedule, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Can't read the schedule {}: {}", path.display(), e)))?;
+    let bad = |n: usize, what: String| {
+        Error::Config(format!("{} line {}: {}", path.display(), n + 1, what))
+    };
+    let mut frames: Vec<(Duration, usize)> = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty());
+        let secs: f64 = fields
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|e| bad(n, format!("offset: {}", e)))?;
+        if !(secs >= 0.0 && secs.is_finite()) {
+            return Err(bad(n, "offsets can't be negative".to_string()));
+        }
+        let size = match fields.next() {
+            Some(size) => size.parse().map_err(|e| bad(n, format!("size: {}", e)))?,
+            None => psize,
+        };
+        if fields.next().is_some() {
+            return Err(bad(n, "expected an offset and a size".to_string()));
+        }
+        let offset = Duration::from_secs_f64(secs);
+        if frames.last().is_some_and(|&(last, _)| offset < last) {
+            return Err(bad(n, "offsets must not go back in time".to_string()));
+        }
+        frames.push((offset, size));
+    }
+    let (first, last) = match (frames.first(), frames.last()) {
+        (Some(first), Some(last)) => (first.0, last.0),
+        _ => {
+            return Err(Error::Config(format!(
+                "The schedule {} lists no frames",
+                path.display()
+            )))
+        }
+    };
+    // One average gap after the last frame, so a round doesn't start on top
+    // of it
+    let lap = match frames.len() {
+        1 => last.max(Duration::from_millis(1)),
+        n => last + (last - first) / (n as u32 - 1),
+    };
+    Ok(Schedule { frames, lap })
+}
+
+// How closely the sends kept to the --schedule: the lateness of each frame
+// against its offset
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+struct Adherence {
+    frames: u64,
+    mean_error_us: f64,
+    max_error_us: f64,
+    late_1ms: u64, // Sent more than 1ms late
+}
+
+impl Adherence {
+    fn record(&mut self, late: Duration) {
+        let us = late.as_secs_f64() * 1e6;
+        self.frames += 1;
+        self.mean_error_us += (us - self.mean_error_us) / self.frames as f64;
+        self.max_error_us = self.max_error_us.max(us);
+        self.late_1ms += (late > Duration::from_millis(1)) as u64;
+    }
+}
+
+// Sleep for the bulk of the wait and spin for the rest, as sleeping alone
+// overshoots by tens of microseconds
+fn wait_until(deadline: Instant) {
+    const SPIN: Duration = Duration::from_micros(100);
+    let now = Instant::now();
+    if deadline > now + SPIN {
+        thread::sleep(deadline - now - SPIN);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+// Ethernet FCS: CRC-32 (IEEE 802.3), sent least significant byte first
+fn fcs(frame: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !frame.iter().fold(!0, |crc: u32, &b| {
+        TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// --train: frames with the test's addresses and size but TRAIN_MAGIC in place
+// of an Id, paced like the test (the probe rate is left to the test itself)
+fn train(tx: &mut Box<dyn datalink::DataLinkSender>, header: &[u8], opts: &Opt) {
+    let mut frame = header.to_vec();
+    frame.resize(ETH_HEADER_SIZE + opts.psize, 0);
+    frame[ETH_HEADER_SIZE..ETH_HEADER_SIZE + TRAIN_MAGIC.len()].copy_from_slice(TRAIN_MAGIC);
+    let mut framed = vec![];
+    let frame = match opts.bad_fcs {
+        Some(_) => with_fcs(&mut framed, &frame, false),
+        None => &frame,
+    };
+    let gap = match opts.ipg_ns {
+        Some(ns) => Duration::from_nanos(ns),
+        None if opts.max_pps => Duration::ZERO,
+        None => Duration::from_secs_f64((8 * opts.psize) as f64 / (opts.bandwidth as f64 * 1e6)),
+    };
+
+    let start = Instant::now();
+    let mut next = start;
+    for sent in 0..opts.train {
+        wait_until(next);
+        next += gap;
+        if let Err(e) = tx.send_to(frame, None).unwrap() {
+            clear_progress();
+            eprintln!("Training stopped after {} frames: {}", sent, e);
+            return;
+        }
+    }
+    report_info(
+        opts,
+        &format!(
+            "Training: sent {} frames in {:.2}s",
+            opts.train,
+            start.elapsed().as_secs_f32()
+        ),
+    );
+}
+
+// Copy `frame` into `buf` followed by its FCS, inverted when `corrupt`
+fn with_fcs<'a>(buf: &'a mut Vec<u8>, frame: &[u8], corrupt: bool) -> &'a [u8] {
+    let mut crc = fcs(frame);
+    if corrupt {
+        crc = !crc;
+    }
+    buf.clear();
+    buf.extend_from_slice(frame);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+// Drivers without IFF_SUPP_NOFCS refuse SO_NOFCS frames at send time
+fn nofcs_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EPROTONOSUPPORT)
+}
+
+fn tx_traffic(
+    tx: &mut Box<dyn datalink::DataLinkSender>,
+    mac_addr_src: MacAddr,
+    opts: Opt,
+) -> Result<RunResult, Error> {
+    let mut dat = vec![0; opts.psize + ETH_HEADER_SIZE];
+    let mut packet = MutableEthernetPacket::new(&mut dat).unwrap();
+    packet.set_ethertype(EtherType::new(opts.ethertype));
+    packet.set_source(mac_addr_src);
+    if opts.dst_self {
+        packet.set_destination(mac_addr_src);
+        report_info(&opts, &format!("Destination: {} (own MAC)", mac_addr_src));
+    } else {
+        packet.set_destination(opts.dest.unwrap());
+    }
+
+    let label = ethertype_label(opts.ethertype);
+    if let Some(sweep) = opts.ethertype_sweep {
+        report_info(
+            &opts,
+            &format!("Sending Ether Types {:x}-{:x}", sweep.start, sweep.end),
+        );
+    } else if !label.is_empty() {
+        report_info(
+            &opts,
+            &format!("Sending Ether Type {:x}{}", opts.ethertype, label),
+        );
+    }
+    if let Some(prio) = opts.tx_priority {
+        report_info(&opts, &format!("TX priority: {}", prio));
+    }
+    if let Some(queue) = opts.tx_queue {
+        match pin_to_cpu(queue) {
+            Ok(()) => report_info(
+                &opts,
+                &format!("TX queue: {} (pinned to CPU {})", queue, queue),
+            ),
+            Err(e) => eprintln!(
+                "Failed to pin TX to CPU {}, queue is not fixed: {}",
+                queue, e
+            ),
+        }
+    }
+    let rt_priority = opts.rt_priority.map(|priority| {
+        let granted = match set_rt_priority(priority) {
+            Ok(()) => {
+                report_info(
+                    &opts,
+                    &format!("TX thread: SCHED_FIFO priority {}", priority),
+                );
+                true
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to set SCHED_FIFO priority {}, sending with normal scheduling: {}",
+                    priority, e
+                );
+                false
+            }
+        };
+        RtPriority { priority, granted }
+    });
+
+    let mut rng = rand::thread_rng();
+
+    let stream = rng.gen();
+    // The receiver answering means it's listening, see --control
+    let mut control = opts
+        .control
+        .and_then(|peer| ControlClient::start(&opts, peer, stream));
+    let ready = since_start();
+    // Before the stream's Tracker exists, so it's left out of the measurement
+    if !opts.start_delay.is_zero() {
+        thread::sleep(opts.start_delay);
+    }
+    if opts.train > 0 {
+        train(tx, &packet.packet()[..ETH_HEADER_SIZE], &opts);
+    }
+    let mut begin = Instant::now();
+    let mut dur = Duration::from_secs(opts.tsecs);
+    let mut bandwidth = opts.bandwidth;
+    let resolution = Duration::from_millis(10);
+    let grace = Duration::from_secs(opts.link_grace);
+    let keepalive = Duration::from_secs_f32(opts.keepalive);
+    let ipg = opts.ipg_ns.map(Duration::from_nanos);
+    // --ipg-ns paces against a timeline from `begin`, so the time spent per
+    // frame doesn't add to every gap
+    let mut next_send = begin;
+    let mut last_sent = Instant::now();
+    let mut last_stamp = last_sent - BUFFERBLOAT_STAMP_INTERVAL;
+    let mut send_errors: u64 = 0; // Over all phases, see --max-errors
+
+    // With --bad-fcs the socket has SO_NOFCS set, so every frame carries its
+    // own FCS in a trailer, a corrupted one for the chosen fraction
+    let mut framed = Vec::with_capacity(opts.psize + ETH_HEADER_SIZE + 4);
+    if let Some(bad) = opts.bad_fcs {
+        report_info(&opts, &format!("Bad FCS fraction: {:.2}", bad));
+    }
+
+    let mut id = Id::new(stream, opts.start_seq);
+
+    let mut tracker = Tracker::new(id.id);
+    tracker.max_samples = opts.max_samples;
+
+    // With --probe-secs the test starts with a probe phase at its own rate,
+    // frames carry the probe flag so the receiver can split its stats too
+    if opts.probe_secs > 0 {
+        dur = Duration::from_secs(opts.probe_secs);
+        bandwidth = opts.probe_bandwidth;
+        id.probe = true;
+        tracker.phase = Some(Phase::Probe);
+    }
+
+    // Leading payload bytes: the Id header and the optional user prefix. The
+    // prefix is written once, per frame only the Id is updated in place.
+    let prefix = opts.payload_prefix.as_ref().map_or(&[][..], |p| &p.0[..]);
+    let id_off = id_offset(&opts);
+    let prefix_off = prefix_offset(&opts);
+    let head_len = (id_off + head_size(&opts)).max(prefix_off + prefix.len());
+    if opts.counter_payload {
+        for (i, b) in packet.payload_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+    }
+    packet.payload_mut()[prefix_off..prefix_off + prefix.len()].copy_from_slice(prefix);
+
+    // Rest of the payload, see --entropy. Bytes before an --id-offset Id are
+    // filled once, the body after the header is refreshed per frame.
+    let body_len = opts.psize - head_len;
+    let gap = if opts.prefix_before_id {
+        prefix.len()
+    } else {
+        0
+    }..id_off;
+    let pool = entropy_pool(&mut rng, opts.entropy, 2 * body_len.max(gap.len()));
+    if opts.entropy > 0.0 {
+        report_info(&opts, &format!("Payload entropy: {:.2}", opts.entropy));
+        packet.payload_mut()[gap.clone()].copy_from_slice(&pool[..gap.len()]);
+    }
+    if let Some(preset) = opts.preset {
+        packet
+            .payload_mut()
+            .copy_from_slice(&preset.payload(mac_addr_src));
+    }
+
+    let schedule = SCHEDULE.get();
+    let mut next_frame = 0; // Of the schedule, counting every round
+    let mut adherence = Adherence::default();
+    let mut aimd = opts.target_drop.map(|pct| Aimd::new(&opts, pct));
+    // Where pacing starts over after --target-drop changed the rate: active
+    // time and bytes sent by then
+    let mut rebased: Option<(Duration, u64)> = None;
+    if let Some(target) = opts.target_drop {
+        report_info(
+            &opts,
+            &format!(
+                "Target drop: {:.2}%, adjusting the rate per interval",
+                target
+            ),
+        );
+    }
+
+    let mut pause = Pause::new("Sending");
+    loop {
+        if let Some(paused) = pause.poll() {
+            begin += paused;
+            next_send += paused;
+            tracker.resume(paused);
+        }
+        if pause.is_paused() {
+            thread::sleep(PAUSE_POLL);
+            continue;
+        }
+        let elapsed = begin.elapsed();
+
+        // Time the link was down doesn't count against the target rate
+        let active = elapsed.saturating_sub(tracker.outage);
+        let cur_rate = match rebased {
+            Some((at, bytes)) => {
+                ((8 * (tracker.total_bytes - bytes)) as f32) / (active - at).as_secs_f32()
+            }
+            None => ((8 * tracker.total_bytes) as f32) / (active.as_secs_f32()),
+        };
+
+        if let (Some(aimd), Some(control)) = (&mut aimd, &mut control) {
+            let feedback = control.interval().filter(|_| !id.probe);
+            if let Some(dropped_pct) = feedback.and_then(|record| record.dropped_pct) {
+                // From what was sent, in case the host couldn't keep up
+                let sent = bandwidth.min(cur_rate / 1_000_000.0);
+                bandwidth = aimd.adjust(sent, dropped_pct);
+                rebased = Some((active, tracker.total_bytes));
+            }
+        }
+
+        // Even when rate limited, send often enough that the receiver doesn't
+        // time the stream out
+        let paced = opts.ipg_ns.is_none()
+            && !opts.max_pps
+            && schedule.is_none()
+            && cur_rate > bandwidth * 1_000_000.0;
+        let heartbeat = !keepalive.is_zero() && last_sent.elapsed() >= keepalive;
+
+        // The next frame of the schedule, None once it's over. Frames past
+        // --tsecs are cut off.
+        let scheduled = schedule.and_then(|s| {
+            let round = next_frame / s.frames.len();
+            let (offset, size) = s.frames[next_frame % s.frames.len()];
+            let offset = offset + s.lap * round as u32;
+            Some((offset, size)).filter(|_| round == 0 || opts.schedule_loop)
+        });
+        let schedule_done = schedule.is_some() && scheduled.is_none();
+        // Sleeps in steps until close to the next frame, so reports and
+        // pauses aren't held up
+        let early =
+            scheduled.is_some_and(|(offset, _)| offset > dur || elapsed + resolution < offset);
+
+        if (paced && !heartbeat) || early {
+            // TODO: Dynamic sleep time calculation?
+            thread::sleep(resolution);
+        } else if !schedule_done {
+            if ipg.is_some() {
+                wait_until(next_send);
+            }
+            if let Some((offset, _)) = scheduled {
+                wait_until(begin + offset);
+                adherence.record(begin.elapsed().saturating_sub(offset));
+            }
+            last_sent = Instant::now();
+            let stamp = if opts.bufferbloat {
+                last_sent.duration_since(last_stamp) >= BUFFERBLOAT_STAMP_INTERVAL
+            } else {
+                opts.timestamp_every > 0 && id.cnt.is_multiple_of(opts.timestamp_every)
+            };
+            if stamp {
+                id.stamped = true;
+                id.ts_ns = unix_ns();
+                last_stamp = last_sent;
+            }
+            let psize = scheduled.map_or(opts.psize, |(_, size)| size);
+            if opts.preset.is_none() {
+                hot(Hot::Serialize, || {
+                    wire()
+                        .serialize_into(&mut packet.payload_mut()[id_off..], &id)
+                        .unwrap()
+                });
+            }
+            if opts.length_check {
+                let off = id_off + Id::size();
+                packet.payload_mut()[off..off + LENGTH_SIZE]
+                    .copy_from_slice(&(psize as u32).to_le_bytes());
+            }
+            if let Some(sweep) = opts.ethertype_sweep {
+                packet.set_ethertype(EtherType::new(sweep.nth(id.cnt)));
+            }
+            if opts.entropy > 0.0 {
+                let off = rng.gen_range(0..=pool.len() - body_len);
+                packet.payload_mut()[head_len..].copy_from_slice(&pool[off..off + body_len]);
+            }
+            let frame = &packet.packet()[..ETH_HEADER_SIZE + psize];
+            let frame = match opts.bad_fcs {
+                Some(bad) => {
+                    let corrupt = rng.gen_bool(bad);
+                    tracker.bad_fcs += corrupt as u64;
+                    with_fcs(&mut framed, frame, corrupt)
+                }
+                None => frame,
+            };
+            match hot(Hot::Send, || {
+                send_frame(tx, frame, grace, &mut tracker.outage)
+            }) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NetworkDown => {
+                    eprintln!("Link down, stopping");
+                    break;
+                }
+                Err(e) if opts.bad_fcs.is_some() && nofcs_unsupported(&e) => {
+                    return Err(Error::Config(format!(
+                        "{} can't send frames without a hardware FCS, --bad-fcs needs a driver with SO_NOFCS support",
+                        opts.ifname
+                    )));
+                }
+                Err(e) if send_errors < opts.max_errors => {
+                    // The frame wasn't sent, retry it with the same sequence
+                    send_errors += 1;
+                    clear_progress();
+                    eprintln!(
+                        "Send error {}/{} ({:?}): {}",
+                        send_errors,
+                        opts.max_errors,
+                        e.kind(),
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    if opts.max_errors > 0 {
+                        eprintln!("Too many send errors, stopping");
+                    }
+                    return Err(Error::Send(e));
+                }
+            }
+            if opts.setup_times && tracker.setup.is_none() {
+                tracker.setup = Some(Setup {
+                    ready_secs: ready,
+                    first_frame_secs: since_start(),
+                });
+            }
+            hot(Hot::Tracker, || tracker.insert(&id, psize as u64));
+            id = id.next();
+            next_frame += 1;
+            if let Some(ipg) = ipg {
+                next_send += ipg;
+            }
+        }
+
+        if let Some(report) = tracker.report_tx() {
+            report_interval(&opts, report);
+        }
+
+        if elapsed > dur && id.probe && !past_deadline() {
+            report_summary(&opts, tracker.report_tx_summary());
+            report_info(&opts, "\nSustained phase:");
+
+            begin = Instant::now();
+            next_send = begin;
+            dur = Duration::from_secs(opts.tsecs);
+            bandwidth = opts.bandwidth;
+            rebased = None;
+            id.probe = false;
+            tracker = Tracker::new(id.id);
+            tracker.max_samples = opts.max_samples;
+            tracker.phase = Some(Phase::Sustained);
+            continue;
+        }
+
+        if elapsed > dur || past_deadline() || schedule_done {
+            if opts.preset.is_some() {
+                break;
+            }
+            // Inform done, stamped for the receiver's --drain-time
+            id.last = true;
+            id.stamped = true;
+            id.ts_ns = unix_ns();
+            hot(Hot::Serialize, || {
+                wire()
+                    .serialize_into(&mut packet.payload_mut()[id_off..], &id)
+                    .unwrap()
+            });
+            if let Some(sweep) = opts.ethertype_sweep {
+                packet.set_ethertype(EtherType::new(sweep.nth(id.cnt)));
+            }
+            let frame = match opts.bad_fcs {
+                Some(_) => with_fcs(&mut framed, packet.packet(), false),
+                None => packet.packet(),
+            };
+            if let Err(e) = send_frame(tx, frame, grace, &mut tracker.outage) {
+                eprintln!("Failed to send the final frame: {}", e);
+            }
+            break;
+        }
+    }
+
+    let mut report = tracker.report_tx_summary();
+    if opts.max_pps {
+        let (peak, avg) = tracker.pps();
+        report.peak_pps = Some(peak);
+        report.avg_pps = Some(avg);
+    }
+    if opts.ipg_ns.is_some() {
+        report.avg_ipg_ns = tracker.avg_ipg_ns();
+    }
+    if opts.bad_fcs.is_some() {
+        report.bad_fcs = Some(tracker.bad_fcs);
+    }
+    if opts.max_errors > 0 {
+        report.send_errors = Some(send_errors);
+    }
+    if schedule.is_some() {
+        report.schedule = Some(adherence);
+    }
+    report.tx_stream = opts.tx_stream;
+    report.rt_priority = rt_priority;
+    let result = RunResult {
+        rate_bps: report.rate_bps,
+        dropped_pct: None,
+        pkts: report.pkts,
+        secs: report.end - report.start,
+        latency: report.latency,
+    };
+    let sent = (report.pkts, report.bytes.unwrap_or(0));
+    report_summary(&opts, report);
+    report_hotpath();
+    if let Some(control) = control {
+        control.finish(&opts, sent);
+    }
+    match aimd.map(|aimd| aimd.converged()) {
+        Some(Some(mut convergence)) => {
+            convergence.unit = opts.units.pick(convergence.rate_bps);
+            clear_progress();
+            print_report(&opts, &convergence);
+        }
+        Some(None) => eprintln!("No interval reports from the receiver, the rate never changed"),
+        None => {}
+    }
+    Ok(result)
+}
+
+// A new stream from the source MAC of an active one replaces it, as the same
+// sender restarted with a new stream id, if the old stream sends nothing for
+// this long after the new one began, or four of its average frame gaps if
+// longer. Streams of a --parallel sender share the MAC but keep interleaving.
+const RESTART_SILENCE: Duration = Duration::from_millis(100);
+
+// Remove and return the streams that `stream` replaced, see RESTART_SILENCE.
+// Its candidates that sent after it began run alongside it and are dropped,
+// the rest wait until their silence is long enough.
+fn take_restarted(trackers: &mut HashMap<u32, Tracker>, stream: u32) -> Vec<Tracker> {
+    let (begin, candidates) = match trackers.get_mut(&stream) {
+        Some(t) if !t.restart_candidates.is_empty() => {
+            (t.begin, mem::take(&mut t.restart_candidates))
+        }
+        _ => return vec![],
+    };
+    let mut replaced = vec![];
+    let mut undecided = vec![];
+    for old in candidates {
+        let t = match trackers.get(&old) {
+            Some(t) => t,
+            None => continue,
+        };
+        if t.pkts.last().is_some_and(|last| last.ts >= begin) {
+            continue;
+        }
+        if begin.elapsed() >= t.restart_wait() {
+            replaced.push(old);
+        } else {
+            undecided.push(old);
+        }
+    }
+    trackers.get_mut(&stream).unwrap().restart_candidates = undecided;
+    replaced
+        .into_iter()
+        .filter_map(|old| trackers.remove(&old))
+        .collect()
+}
+
+// How long frames of a finished stream are ignored instead of starting a
+// new one, so stragglers arriving after the sentinel or the idle timeout don't
+// show up as phantom streams
+const CLOSED_GRACE: Duration = Duration::from_secs(10);
+
+// Operational state of the interface, if the platform exposes it
+fn link_is_up(ifname: &str) -> Option<bool> {
+    let path = format!("/sys/class/net/{}/operstate", ifname);
+    let state = std::fs::read_to_string(path).ok()?;
+    Some(matches!(state.trim(), "up" | "unknown"))
+}
+
+// Interval between operational state checks of --wait-for-link
+const LINK_POLL: Duration = Duration::from_millis(100);
+
+// --wait-for-link: block until all interfaces the test uses are up, or give up
+// after `timeout`. Interfaces whose state can't be read don't hold the test up,
+// opening them reports what's wrong.
+fn wait_for_link(opts: &Opt, timeout: Duration) -> Result<(), Error> {
+    let ifnames: Vec<&str> = match (&opts.tx_ifname, &opts.rx_ifname) {
+        (Some(tx), Some(rx)) => vec![tx, rx],
+        _ => vec![&opts.ifname],
+    };
+    let begin = Instant::now();
+    let mut announced = false;
+    while let Some(down) = ifnames
+        .iter()
+        .find(|ifname| link_is_up(ifname) == Some(false))
+    {
+        if begin.elapsed() >= timeout {
+            return Err(Error::Config(format!(
+                "Link on {} still down after {}s",
+                down,
+                timeout.as_secs()
+            )));
+        }
+        if !announced {
+            announced = true;
+            clear_progress();
+            eprintln!(
+                "Link on {} is down, waiting up to {}s...",
+                down,
+                timeout.as_secs()
+            );
+        }
+        thread::sleep(LINK_POLL);
+    }
+    if announced {
+        report_info(
+            opts,
+            &format!("Link up after {:.1}s", begin.elapsed().as_secs_f32()),
+        );
+    }
+    Ok(())
+}
+
+// Set by the --deadline watchdog. The TX and RX loops finish as if the test
+// had ended, and sweeps and repeats don't start another run.
+static DEADLINE_HIT: AtomicBool = AtomicBool::new(false);
+
+// How long the loops get to wind down after the deadline before the process
+// exits regardless, enough for a blocked read to time out
+const DEADLINE_GRACE: Duration = Duration::from_secs(5);
+
+// Exit status of a run stopped by --deadline, as timeout(1) uses
+const DEADLINE_EXIT: i32 = 124;
+
+fn past_deadline() -> bool {
+    DEADLINE_HIT.load(Ordering::Relaxed)
+}
+
+fn start_deadline(secs: u64) {
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(secs).saturating_sub(START.get().unwrap().elapsed()));
+        DEADLINE_HIT.store(true, Ordering::Relaxed);
+        clear_progress();
+        eprintln!("Deadline of {}s reached, stopping", secs);
+        thread::sleep(DEADLINE_GRACE);
+        clear_progress();
+        eprintln!(
+            "Still running {}s after the deadline, exiting",
+            DEADLINE_GRACE.as_secs()
+        );
+        io::stdout().flush().ok();
+        process::exit(DEADLINE_EXIT);
+    });
+}
+
+// For --strict-sequence every frame has to follow the previous one
+// exactly. Up to then every frame from the lowest to the highest arrived, so
+// one of those again is a duplicate and only an older one is out of order.
+fn check_sequence(tracker: &Tracker, id: &Id) -> Result<(), String> {
+    let (lowest, expected) = match (tracker.lowest, tracker.highest) {
+        (Some(lowest), Some(highest)) => (lowest, highest.wrapping_add(1)),
+        _ => return Ok(()),
+    };
+    if id.cnt == expected {
+        Ok(())
+    } else if !seq_before(id.cnt, lowest) && seq_before(id.cnt, expected) {
+        Err(format!("duplicate frame {}", id.cnt))
+    } else if seq_before(id.cnt, lowest) {
+        Err(format!(
+            "out of order frame {}, expected {}",
+            id.cnt, expected
+        ))
+    } else {
+        Err(format!(
+            "lost frames {}-{}, got {}",
+            expected,
+            id.cnt.wrapping_sub(1),
+            id.cnt
+        ))
+    }
+}
+
+// --handshake frames start with HELLO_MAGIC at payload offset 0, whatever the
+// Id offset. Bump PROTOCOL_VERSION on any change to the Id or Hello layout.
+const HELLO_MAGIC: &[u8; 8] = b"L2PHELLO";
+// Start of the payload of --train frames, which receivers drop unseen
+const TRAIN_MAGIC: &[u8; 8] = b"L2PTRAIN";
+const PROTOCOL_VERSION: u16 = 1;
+
+// Capability bits, see Hello::caps
+const CAP_TIMESTAMPS: u32 = 1 << 0; // Latency from Id timestamps, --timestamp-every
+const CAP_PROBE: u32 = 1 << 1; // Probe/sustained split, --probe-secs
+const CAP_VLAN: u32 = 1 << 2; // Per-VLAN stats, the receiver runs --raw-capture
+
+const CAP_NAMES: &[(u32, &str)] = &[
+    (CAP_TIMESTAMPS, "timestamps"),
+    (CAP_PROBE, "probe"),
+    (CAP_VLAN, "vlan"),
+];
+
+fn cap_names(caps: u32) -> String {
+    let names: Vec<&str> = CAP_NAMES
+        .iter()
+        .filter(|(bit, _)| caps & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+// Capabilities frame. The sender asks with `reply` unset and the receiver
+// answers with its own version and capabilities; the test then uses the
+// intersection and is refused if a `required` bit is missing on either end.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    magic: [u8; 8],
+    version: u16,
+    caps: u32,
+    required: u32,
+    reply: bool,
+    mac: [u8; 6], // Of the sender, Layer3 capture doesn't deliver the header
+}
+
+impl Hello {
+    fn new(caps: u32, required: u32, reply: bool, mac: MacAddr) -> Self {
+        Self {
+            magic: *HELLO_MAGIC,
+            version: PROTOCOL_VERSION,
+            caps,
+            required,
+            reply,
+            mac: mac.octets(),
+        }
+    }
+
+    fn parse(payload: &[u8]) -> Option<Self> {
+        if !payload.starts_with(HELLO_MAGIC) {
+            return None;
+        }
+        wire().deserialize(payload).ok()
+    }
+
+    // Ethernet frame of this Hello to `dst`, padded to the minimum size
+    fn frame(&self, src: MacAddr, dst: MacAddr, ethertype: u16) -> Vec<u8> {
+        let mut dat = vec![0; 60];
+        let mut packet = MutableEthernetPacket::new(&mut dat).unwrap();
+        packet.set_source(src);
+        packet.set_destination(dst);
+        packet.set_ethertype(EtherType::new(ethertype));
+        wire().serialize_into(packet.payload_mut(), self).unwrap();
+        dat
+    }
+}
+
+// What this build supports with the given options
+fn local_caps(opts: &Opt) -> u32 {
+    let mut caps = CAP_TIMESTAMPS | CAP_PROBE;
+    if opts.raw_capture {
+        caps |= CAP_VLAN;
+    }
+    caps
+}
+
+// What the sender's options need from the receiver
+fn required_caps(opts: &Opt) -> u32 {
+    let mut required = 0;
+    if opts.timestamp_every > 0 || opts.bufferbloat {
+        required |= CAP_TIMESTAMPS;
+    }
+    if opts.probe_secs > 0 {
+        required |= CAP_PROBE;
+    }
+    required
+}
+
+// TX side of --handshake: send a Hello until the receiver answers or
+// RX_TIMEOUT passes, then check its version and capabilities
+fn handshake(opts: &Opt) -> Result<(), Error> {
+    let (interface, mut tx, _) = open_channel(&opts.ifname, None, opts)?;
+    let mut rx_opts = opts.clone();
+    rx_opts.raw_capture = false;
+    let (_, _, mut rx) = open_channel(&opts.ifname, Some(opts.ethertype), &rx_opts)?;
+    let src = interface.mac.unwrap_or_default();
+    let dest = if opts.dst_self {
+        src
+    } else {
+        opts.dest.unwrap()
+    };
+
+    let caps = local_caps(opts);
+    let required = required_caps(opts);
+    let hello = Hello::new(caps, required, false, src).frame(src, dest, opts.ethertype);
+    report_info(
+        opts,
+        &format!(
+            "Handshake with {}, requiring: {}",
+            dest,
+            cap_names(required)
+        ),
+    );
+
+    let deadline = Instant::now() + RX_TIMEOUT;
+    let peer = 'wait: loop {
+        if Instant::now() >= deadline {
+            return Err(Error::Handshake(format!(
+                "no reply from {} within {}s, is a receiver with --handshake support running there?",
+                dest,
+                RX_TIMEOUT.as_secs()
+            )));
+        }
+        tx.send_to(&hello, None).unwrap().map_err(Error::Send)?;
+        let resend = Instant::now() + Duration::from_millis(200);
+        while Instant::now() < resend {
+            match rx.next() {
+                Ok(payload) => match Hello::parse(payload) {
+                    Some(peer) if peer.reply && MacAddr::from(peer.mac) == dest => {
+                        break 'wait peer
+                    }
+                    _ => {}
+                },
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(Error::Receive(e)),
+            }
+        }
+    };
+
+    if peer.version != PROTOCOL_VERSION {
+        return Err(Error::Handshake(format!(
+            "protocol version mismatch, local v{}, peer v{}",
+            PROTOCOL_VERSION, peer.version
+        )));
+    }
+    let missing = required & !peer.caps;
+    if missing != 0 {
+        return Err(Error::Handshake(format!(
+            "the receiver doesn't support: {}",
+            cap_names(missing)
+        )));
+    }
+    report_info(
+        opts,
+        &format!(
+            "Handshake: peer v{}, common capabilities: {}",
+            peer.version,
+            cap_names(caps & peer.caps)
+        ),
+    );
+    Ok(())
+}
+
+// RX side of --handshake, answer with our version and capabilities. The
+// sender compares them, but a version mismatch is worth a note here too.
+fn answer_hello(opts: &Opt, hello: &Hello) {
+    if hello.version != PROTOCOL_VERSION {
+        clear_progress();
+        eprintln!(
+            "Handshake from {} with protocol v{}, this is v{}",
+            MacAddr::from(hello.mac),
+            hello.version,
+            PROTOCOL_VERSION
+        );
+    }
+    let sent = open_channel(&opts.ifname, None, opts).and_then(|(interface, mut tx, _)| {
+        let src = interface.mac.unwrap_or_default();
+        let reply = Hello::new(local_caps(opts), hello.required, true, src);
+        let frame = reply.frame(src, MacAddr::from(hello.mac), opts.ethertype);
+        tx.send_to(&frame, None).unwrap().map_err(Error::Send)
+    });
+    if let Err(e) = sent {
+        clear_progress();
+        eprintln!("Failed to answer a handshake: {}", e);
+    }
+}
+
+// --control datagrams, encoded as in `wire`. The sender repeats Start until
+// the receiver answers Ready, and Stop until it gets the stream's Summary,
+// which the receiver also sends unasked once it has one, followed by the
+// stream's Counts. In between the receiver sends its interval reports, for
+// --target-drop.
+#[derive(Debug, Serialize, Deserialize)]
+enum Control {
+    Start {
+        version: u16,
+        stream: u32,
+    },
+    Ready {
+        stream: u32,
+    },
+    Stop {
+        stream: u32,
+    },
+    Summary(ExportRecord),
+    Interval(ExportRecord),
+    Counts {
+        stream: u32,
+        phase: Option<Phase>,
+        received: u64,
+        duplicates: u64,
+        bytes: u64,
+    },
+}
+
+// How long a sender waits for the receiver's summary after its last frame,
+// the receiver may first have to time the stream out
+const CONTROL_WAIT: Duration = Duration::from_secs(3 * RX_TIMEOUT.as_secs());
+
+// Resend interval of Start and Stop
+const CONTROL_RETRY: Duration = Duration::from_millis(200);
+
+fn send_control(socket: &UdpSocket, msg: &Control, to: SocketAddr) -> io::Result<()> {
+    socket
+        .send_to(&wire().serialize(msg).unwrap(), to)
+        .map(|_| ())
+}
+
+// Datagrams that aren't Control messages are skipped, errors other than the
+// read timeout end the wait
+fn recv_control(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Option<(Control, SocketAddr)>> {
+    match socket.recv_from(buf) {
+        Ok((len, from)) => Ok(wire().deserialize(&buf[..len]).ok().map(|msg| (msg, from))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// TX side of --control for one stream
+struct ControlClient {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    stream: u32,
+    last_poll: Instant,
+}
+
+// How often the TX loop looks for the receiver's interval reports
+const CONTROL_POLL: Duration = Duration::from_millis(100);
+
+impl ControlClient {
+    // Wait for the receiver to be ready for `stream`. Without an answer the
+    // test goes ahead, only without the receiver's summary.
+    fn start(opts: &Opt, peer: SocketAddr, stream: u32) -> Option<Self> {
+        let bind: SocketAddr = if peer.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = match UdpSocket::bind(bind) {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!(
+                    "Failed to open the control socket: {}, continuing without it",
+                    e
+                );
+                return None;
+            }
+        };
+        socket.set_read_timeout(Some(CONTROL_RETRY)).ok();
+        let mut client = Self {
+            socket,
+            peer,
+            stream,
+            last_poll: Instant::now(),
+        };
+        let start = Control::Start {
+            version: PROTOCOL_VERSION,
+            stream,
+        };
+        let deadline = Instant::now() + RX_TIMEOUT;
+        let mut buf = [0; 1500];
+        while Instant::now() < deadline {
+            let answer = send_control(&client.socket, &start, peer)
+                .and_then(|()| recv_control(&client.socket, &mut buf));
+            match answer {
+                Ok(Some((Control::Ready { stream: s }, from))) if s == stream && from == peer => {
+                    report_info(opts, &format!("Control: receiver at {} is ready", peer));
+                    // Polled from the TX loop until finish()
+                    client.socket.set_nonblocking(true).ok();
+                    client.last_poll = Instant::now();
+                    return Some(client);
+                }
+                Ok(_) => {}
+                // Typically ECONNREFUSED from the ICMP error of an earlier datagram
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => thread::sleep(CONTROL_RETRY),
+                Err(e) => {
+                    eprintln!("Control channel to {}: {}, continuing without it", peer, e);
+                    return None;
+                }
+            }
+        }
+        eprintln!(
+            "No answer on the control channel from {} within {}s, continuing without it",
+            peer,
+            RX_TIMEOUT.as_secs()
+        );
+        None
+    }
+
+    // The receiver's latest interval report of the stream since the last
+    // call, at most every CONTROL_POLL
+    fn interval(&mut self) -> Option<ExportRecord> {
+        if self.last_poll.elapsed() < CONTROL_POLL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+        let mut buf = [0; 1500];
+        let mut latest = None;
+        // Errors are left to finish(), which reports them
+        while let Ok(Some((msg, from))) = recv_control(&self.socket, &mut buf) {
+            match msg {
+                Control::Interval(record) if record.stream == self.stream && from == self.peer => {
+                    latest = Some(record)
+                }
+                _ => {}
+            }
+        }
+        latest
+    }
+
+    // Fetch and print the receiver's summary of the stream, then hold its
+    // counts against the `sent` frames and bytes
+    fn finish(self, opts: &Opt, sent: (u64, u64)) {
+        self.socket.set_nonblocking(false).ok();
+        let stop = Control::Stop {
+            stream: self.stream,
+        };
+        let mut deadline = Instant::now() + CONTROL_WAIT;
+        let mut buf = [0; 1500];
+        let (mut summary, mut counts) = (None, None);
+        while Instant::now() < deadline && (summary.is_none() || counts.is_none()) {
+            let answer = send_control(&self.socket, &stop, self.peer)
+                .and_then(|()| recv_control(&self.socket, &mut buf));
+            match answer {
+                // A probe summary comes first with --probe-secs, wait for the last one
+                Ok(Some((Control::Summary(record), _)))
+                    if record.stream == self.stream
+                        && record.phase != Some(Phase::Probe)
+                        && summary.is_none() =>
+                {
+                    let mut report = record.report();
+                    report.unit = opts.units.pick(report.rate_bps);
+                    report_info(opts, &format!("\nReceiver at {}:", self.peer));
+                    print_report(opts, &report);
+                    summary = Some(record);
+                    // The counts follow right away, receivers before them
+                    // never send any
+                    deadline = deadline.min(Instant::now() + 2 * CONTROL_RETRY);
+                }
+                Ok(Some((
+                    Control::Counts {
+                        stream,
+                        phase,
+                        received,
+                        duplicates,
+                        bytes,
+                    },
+                    _,
+                ))) if stream == self.stream && phase != Some(Phase::Probe) => {
+                    counts = Some((received - duplicates, bytes))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => thread::sleep(CONTROL_RETRY),
+                Err(e) => {
+                    eprintln!("Control channel to {}: {}", self.peer, e);
+                    return;
+                }
+            }
+        }
+        match (summary, counts) {
+            // The receiver's summary leaves out the probe phase, the sender's
+            // count doesn't
+            (Some(_), _) if opts.probe_secs > 0 => {}
+            (Some(summary), Some(received)) => {
+                let check = CrossCheck::new(self.stream, sent, received, &summary);
+                clear_progress();
+                print_report(opts, &check);
+            }
+            (Some(_), None) => {}
+            (None, _) => eprintln!(
+                "No summary on the control channel from {} within {}s",
+                self.peer,
+                CONTROL_WAIT.as_secs()
+            ),
+        }
+    }
+}
+
+// --control: the frames the sender sent against those the receiver got, an
+// end-to-end loss that doesn't depend on sequence gaps. The gaps miss frames
+// lost before the first or after the last one to arrive.
+#[derive(Debug, Serialize)]
+struct CrossCheck {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    stream: u32,
+    sent: u64,
+    sent_bytes: u64,
+    received: u64,       // Duplicates left out
+    received_bytes: u64, // Duplicates included
+    lost: i64,           // Negative if the receiver got frames that weren't sent
+    lost_pct: f32,
+    inferred_lost: u64, // From the receiver's sequence gaps
+    inferred_pct: f32,
+}
+
+impl CrossCheck {
+    fn new(stream: u32, sent: (u64, u64), received: (u64, u64), summary: &ExportRecord) -> Self {
+        let lost = sent.0 as i64 - received.0 as i64;
+        let expected = summary.expected.unwrap_or(0);
+        let inferred_pct = summary.dropped_pct.unwrap_or(0.0);
+        Self {
+            kind: "cross_check",
+            stream,
+            sent: sent.0,
+            sent_bytes: sent.1,
+            received: received.0,
+            received_bytes: received.1,
+            lost,
+            lost_pct: if sent.0 > 0 {
+                lost as f32 / sent.0 as f32 * 100.0
+            } else {
+                0.0
+            },
+            inferred_lost: (expected as f64 * inferred_pct as f64 / 100.0).round() as u64,
+            inferred_pct,
+        }
+    }
+}
+
+impl fmt::Display for CrossCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "End to end: sent {} pkts ({} bytes), received {} ({} bytes), lost {} ({}%)",
+            self.sent,
+            self.sent_bytes,
+            self.received,
+            self.received_bytes,
+            self.lost,
+            Num(self.lost_pct, 2)
+        )?;
+        write!(
+            f,
+            "\nSequence gaps: {} lost ({}%)",
+            self.inferred_lost,
+            Num(self.inferred_pct, 2)
+        )?;
+        let missed = self.lost - self.inferred_lost as i64;
+        if missed > 0 {
+            write!(
+                f,
+                "\nThe sequence gaps miss {} lost frames, lost before the first or after the last to arrive",
+                missed
+            )?;
+        } else if missed < 0 {
+            write!(
+                f,
+                "\nThe sequence gaps count {} frames more than went missing, late frames or numbers the sender skipped",
+                -missed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// --target-drop: additive increase while the receiver drops less than the
+// target, multiplicative decrease while it drops more, within the bounds
+struct Aimd {
+    target_pct: f32,
+    step: f32, // Mbps, like --bandwidth
+    backoff: f32,
+    min: f32,
+    max: f32,
+    history: Vec<(f32, f32)>, // Rate in Mbps and the drop percentage it gave
+}
+
+impl Aimd {
+    fn new(opts: &Opt, target_pct: f32) -> Self {
+        Self {
+            target_pct,
+            step: opts.aimd_step.unwrap_or(opts.bandwidth / 20.0),
+            backoff: opts.aimd_backoff,
+            min: opts.min_bandwidth.unwrap_or(opts.bandwidth / 100.0),
+            max: opts.bandwidth,
+            history: vec![],
+        }
+    }
+
+    // The next rate after `rate` gave `dropped_pct`
+    fn adjust(&mut self, rate: f32, dropped_pct: f32) -> f32 {
+        self.history.push((rate, dropped_pct));
+        let next = if dropped_pct > self.target_pct {
+            rate * (1.0 - self.backoff)
+        } else {
+            rate + self.step
+        };
+        next.clamp(self.min, self.max)
+    }
+
+    // The rate and drop averaged over the second half of the intervals, once
+    // the controller has had time to find the target
+    fn converged(&self) -> Option<Convergence> {
+        let settled = &self.history[self.history.len() / 2..];
+        if settled.is_empty() {
+            return None;
+        }
+        let n = settled.len() as f32;
+        Some(Convergence {
+            kind: "target_drop",
+            target_pct: self.target_pct,
+            rate_bps: settled.iter().map(|(rate, _)| rate).sum::<f32>() / n * 1_000_000.0,
+            dropped_pct: settled.iter().map(|(_, pct)| pct).sum::<f32>() / n,
+            intervals: settled.len(),
+            unit: Unit::Mbps,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Convergence {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    target_pct: f32,
+    rate_bps: f32,
+    dropped_pct: f32, // Reported by the receiver at that rate
+    intervals: usize, // Averaged over
+    #[serde(serialize_with = "serialize_bps")]
+    unit: Unit,
+}
+
+impl fmt::Display for Convergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Target drop {}%: converged at {} {} with {}% dropped (last {} intervals)",
+            Num(self.target_pct, 2),
+            Num(self.rate_bps / self.unit.divisor(), 2),
+            self.unit,
+            Num(self.dropped_pct, 2),
+            self.intervals
+        )
+    }
+}
+
+// RX side of --control: the senders of the streams and the encoded Summary
+// and Counts messages made so far, to answer a repeated Stop
+struct ControlServer {
+    socket: UdpSocket,
+    peers: HashMap<u32, SocketAddr>,
+    summaries: HashMap<u32, Vec<Vec<u8>>>,
+}
+
+static CONTROL: Mutex<Option<ControlServer>> = Mutex::new(None);
+
+fn serve_control(opts: &Opt, addr: SocketAddr) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    let reader = socket.try_clone()?;
+    *CONTROL.lock().unwrap() = Some(ControlServer {
+        socket,
+        peers: HashMap::new(),
+        summaries: HashMap::new(),
+    });
+    report_info(opts, &format!("Control channel on {}", addr));
+
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        loop {
+            let (msg, from) = match recv_control(&reader, &mut buf) {
+                Ok(Some(received)) => received,
+                Ok(None) => continue,
+                // Errors from an ICMP answer to an earlier reply, the next
+                // datagram is unaffected
+                Err(_) => continue,
+            };
+            let mut control = CONTROL.lock().unwrap();
+            let server = control.as_mut().unwrap();
+            match msg {
+                Control::Start { version, stream } => {
+                    if version != PROTOCOL_VERSION {
+                        clear_progress();
+                        eprintln!(
+                            "Control: {} uses protocol v{}, this is v{}",
+                            from, version, PROTOCOL_VERSION
+                        );
+                    }
+                    server.peers.insert(stream, from);
+                    send_control(&server.socket, &Control::Ready { stream }, from).ok();
+                }
+                // Kept for another Stop in case this reply is lost
+                Control::Stop { stream } => {
+                    for reply in server.summaries.get(&stream).into_iter().flatten() {
+                        server.socket.send_to(reply, from).ok();
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+// Hand a receiver interval report to the stream's sender, if it came through
+// --control
+fn control_interval(report: &Report) {
+    if let Some(server) = CONTROL.lock().unwrap().as_ref() {
+        if let Some(&peer) = server.peers.get(&report.stream) {
+            let msg = Control::Interval(ExportRecord::new(report));
+            send_control(&server.socket, &msg, peer).ok();
+        }
+    }
+}
+
+// Same for a summary, kept to answer a repeated Stop
+fn control_summary(report: &Report) {
+    if let Some(server) = CONTROL.lock().unwrap().as_mut() {
+        if let Some(&peer) = server.peers.get(&report.stream) {
+            let counts = Control::Counts {
+                stream: report.stream,
+                phase: report.phase,
+                received: report.pkts,
+                duplicates: report.duplicates.unwrap_or(0),
+                bytes: report.bytes.unwrap_or(0),
+            };
+            let replies = vec![
+                wire()
+                    .serialize(&Control::Summary(ExportRecord::new(report)))
+                    .unwrap(),
+                wire().serialize(&counts).unwrap(),
+            ];
+            for reply in &replies {
+                server.socket.send_to(reply, peer).ok();
+            }
+            server.summaries.insert(report.stream, replies);
+        }
+    }
+}
+
+fn rx_tracker(stream: u32, opts: &Opt) -> Tracker {
+    let mut tracker = Tracker::new(stream);
+    tracker.window = Some(SeqWindow::new());
+    tracker.gaps = Some(GapHistogram::new());
+    if opts.allan {
+        tracker.allan = Some(AllanDev::new());
+    }
+    tracker.fdv = opts
+        .fdv_rate
+        .map(|mbps| FdvClock::new(mbps as f64 * 1_000_000.0));
+    tracker.max_samples = opts.max_samples;
+    tracker.min_interval_pkts = opts.min_interval_pkts;
+    tracker.reorder_window = opts.reorder_window;
+    if opts.payload_dups {
+        tracker.payloads = Some(PayloadWindow::default());
+    }
+    // Goodput only tells more than the rate when frames are checked
+    tracker.interval_goodput = opts.counter_payload || opts.reorder_window.is_some();
+    if opts.log_drops {
+        tracker.drop_log = Some(DropLog::default());
+    }
+    if opts.raw_capture {
+        tracker.vlans = Some(BTreeMap::new());
+    }
+    if opts.ethertype_sweep.is_some() {
+        tracker.ethertypes = Some(BTreeMap::new());
+    }
+    tracker
+}
+
+// Prints the summary and returns its headline numbers. `wrong_ethertype` is
+// the receiver's count so far.
+fn report_rx_done(tracker: &Tracker, opts: &Opt, wrong_ethertype: u64) -> RunResult {
+    let mut report = tracker.report_rx_summary();
+    if opts.raw_capture {
+        report.wrong_ethertype = Some(wrong_ethertype);
+        report.vlans = tracker.vlan_reports(report.end - report.start);
+    }
+    if opts.counter_payload {
+        report.corrupt = Some(tracker.corrupt);
+    }
+    if opts.length_check {
+        let (frames, missing) = tracker.truncated;
+        report.truncated = Some(Truncation {
+            frames,
+            avg_bytes_lost: missing as f64 / frames.max(1) as f64,
+        });
+    }
+    if let Some(sweep) = opts.ethertype_sweep {
+        report.ethertype_sweep = tracker.ethertype_reports(sweep);
+    }
+    report.ethertype = tracker.ethertype;
+    if opts.interval_histogram {
+        report.interval_drops = Some(tracker.interval_histogram());
+    }
+    report.injected_delay_us = opts.inject_delay.map(|ms| ms * 1e3);
+    if opts.unique_rate {
+        report.unique_rate_bps = Some(tracker.unique_rate(report.rate_bps));
+    }
+    if opts.drain_time {
+        report.drain = Some(tracker.drain());
+    }
+    report.rx_thread = opts.rx_thread;
+    report.restart_of = tracker.restart_of;
+    report.restarted = Some(true).filter(|_| tracker.restarted);
+    if opts.rx_load {
+        report.rx_load = tracker.rx_load(report.end - report.start, report.pkts);
+    }
+    let result = RunResult {
+        rate_bps: report.rate_bps,
+        dropped_pct: report.dropped_pct,
+        pkts: report.pkts,
+        secs: report.end - report.start,
+        latency: report.latency,
+    };
+    if let Some(path) = &opts.receive_only_stats {
+        if let Err(e) = append_stats_row(path, opts, &report) {
+            clear_progress();
+            eprintln!("Failed to append to {}: {}", path.display(), e);
+        }
+    }
+    let report = report_summary(opts, report);
+    report_hotpath();
+    control_summary(&report);
+    if let Some(dir) = &opts.output_dir {
+        if let Err(e) = write_output_files(dir, opts, &report, tracker.src) {
+            clear_progress();
+            eprintln!("Failed to write to {}: {}", dir.display(), e);
+        }
+    }
+    if let (Some(thread), Some(tx)) = (opts.rx_thread, RX_RESULTS.lock().unwrap().as_ref()) {
+        tx.send((thread, result)).ok();
+    }
+    result
+}
+
+// --startup-timeout: nothing arrived, say what to check. `frames` are those
+// seen without an Id, of other ethertypes with --raw-capture.
+fn startup_timed_out(opts: &Opt, secs: u64, frames: u64) -> Result<(), Error> {
+    let mac = datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == opts.ifname)
+        .and_then(|iface| iface.mac)
+        .map_or("its MAC".to_string(), |mac| mac.to_string());
+    let mut msg = format!(
+        "No test frames on {} within {}s. Check that:\n  \
+         the sender is running and sends out of the interface linked to {}\n  \
+         it sends to {} or a broadcast address\n  \
+         both ends use Ether Type {:x} (--ethertype)",
+        opts.ifname, secs, opts.ifname, mac, opts.ethertype
+    );
+    if frames > 0 {
+        msg += &format!("\n{} other frames arrived", frames);
+    }
+    msg += "\n--sniff lists the ethertypes arriving on the interface";
+    if opts.startup_fail {
+        return Err(Error::Config(msg));
+    }
+    clear_progress();
+    eprintln!("{}\nStill waiting...", msg);
+    Ok(())
+}
+
+// With `tx_done` set (loopback mode), return after the first stream is
+// summarized or once the local sender has finished and the channel is idle.
+fn rx_traffic(
+    rx: &mut Box<dyn datalink::DataLinkReceiver>,
+    opts: Opt,
+    tx_done: Option<Arc<AtomicBool>>,
+) -> Result<Option<RunResult>, Error> {
+    let mut trackers: HashMap<u32, Tracker> = HashMap::new();
+    let mut closed: HashMap<u32, (Instant, u64)> = HashMap::new(); // (closed at, late frames)
+    let mut frame_no: u64 = 0;
+    let mut last_stream = None;
+    let grace = Duration::from_secs(opts.link_grace);
+    let mut down_since: Option<Instant> = None;
+    let mut result = None; // Of the last summarized stream
+    let mut malformed: u64 = 0; // Frames too short or garbled for an Id
+                                // Frames of other ethertypes, see --raw-capture. Counted for the whole
+                                // receiver, streams or not, and reported in each stream's summary.
+    let mut wrong_ethertype: u64 = 0;
+    let ready = since_start();
+    // Until the first test frame, for --startup-timeout. A --rx-threads
+    // thread may be idle while the others are busy, so they don't check.
+    let mut waiting_since = opts
+        .startup_timeout
+        .filter(|_| opts.rx_thread.is_none())
+        .map(|_| Instant::now());
+
+    if let Some(cpu) = opts.affinity_irq {
+        match pin_to_cpu(cpu) {
+            Ok(()) => {
+                report_info(&opts, &format!("RX pinned to CPU {}", cpu));
+                steer_irqs(&opts, cpu);
+            }
+            Err(e) => eprintln!("Failed to pin RX to CPU {}: {}", cpu, e),
+        }
+    }
+
+    if opts.rx_thread.unwrap_or(0) == 0 {
+        let accepting = match (opts.ethertype_sweep, &opts.stream_ethertypes) {
+            (Some(sweep), _) => format!("Ether Types {:x}-{:x}", sweep.start, sweep.end),
+            (None, Some(list)) => {
+                let list: Vec<String> = list.0.iter().map(|e| format!("{:x}", e)).collect();
+                format!("Ether Types {}", list.join(", "))
+            }
+            (None, None) => format!(
+                "Ether Type {:x}{}",
+                opts.ethertype,
+                ethertype_label(opts.ethertype)
+            ),
+        };
+        report_info(&opts, &format!("Accepting {}...", accepting));
+    }
+
+    let mut pause = Pause::new("Receiving");
+    loop {
+        let next = rx.next();
+        // After the read, a pause that began while it blocked is seen before
+        // the timeout below closes the streams
+        let resumed = pause.poll();
+        if let Some(paused) = resumed {
+            for t in trackers.values_mut() {
+                t.resume(paused);
+            }
+        }
+        if past_deadline() {
+            for t in trackers.values() {
+                result = Some(report_rx_done(t, &opts, wrong_ethertype));
+            }
+            return Ok(result);
+        }
+        match next {
+            Ok(packet_raw) => {
+                let proc_start = if opts.rx_load && frame_no.is_multiple_of(64) {
+                    Some(Instant::now())
+                } else {
+                    None
+                };
+                if let Some(since) = down_since.take() {
+                    eprintln!("Link up after {:.2}s", since.elapsed().as_secs_f32());
+                    for t in trackers.values_mut() {
+                        t.outage += since.elapsed();
+                    }
+                }
+
+                // With --sample N only every Nth frame is decoded, the others
+                // are just counted for the stream seen last
+                let sampled = frame_no.is_multiple_of(opts.sample);
+                frame_no += 1;
+
+                let frame = match hot(Hot::Serialize, || parse_frame(packet_raw, &opts, sampled)) {
+                    Ok(frame) => frame,
+                    Err(Error::WrongEthertype(_)) => {
+                        // Not ours, but worth knowing about
+                        wrong_ethertype += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        if malformed == 0 {
+                            clear_progress();
+                            eprintln!("Ignoring {}", e);
+                        }
+                        malformed += 1;
+                        continue;
+                    }
+                };
+                let (len, vlan, src, ethertype, corrupt_at, hash, sent_len) = (
+                    frame.len,
+                    frame.vlan,
+                    frame.src,
+                    frame.ethertype,
+                    frame.corrupt_at,
+                    frame.hash,
+                    frame.sent_len,
+                );
+                let id = match frame.body {
+                    Body::Id(id) => id,
+                    Body::Hello(hello) => {
+                        if !hello.reply {
+                            answer_hello(&opts, &hello);
+                        }
+                        continue;
+                    }
+                    Body::Training => continue,
+                    Body::Skipped => {
+                        if let Some(t) = last_stream.and_then(|s| trackers.get_mut(&s)) {
+                            t.skip(len as u64);
+                        }
+                        continue;
+                    }
+                };
+
+                if let Some((at, late)) = closed.get_mut(&id.id) {
+                    if at.elapsed() < CLOSED_GRACE {
+                        if *late == 0 {
+                            clear_progress();
+                            eprintln!("Ignoring late frames of finished stream {:x}", id.id);
+                        }
+                        *late += 1;
+                        continue;
+                    }
+                    closed.remove(&id.id);
+                }
+
+                for mut t in take_restarted(&mut trackers, id.id) {
+                    clear_progress();
+                    eprintln!(
+                        "Sender restart detected: stream {:x} from {} replaced by {:x}",
+                        t.stream,
+                        t.src.unwrap(),
+                        id.id
+                    );
+                    t.restarted = true;
+                    result = Some(report_rx_done(&t, &opts, wrong_ethertype));
+                    closed.insert(t.stream, (Instant::now(), 0));
+                    trackers.get_mut(&id.id).unwrap().restart_of = Some(t.stream);
+                }
+                // Streams this one may have replaced, decided by take_restarted
+                let candidates: Vec<u32> = match src {
+                    Some(src) if !trackers.contains_key(&id.id) => trackers
+                        .iter()
+                        .filter(|(_, t)| t.src == Some(src))
+                        .map(|(&stream, _)| stream)
+                        .collect(),
+                    _ => vec![],
+                };
+
+                last_stream = Some(id.id);
+                let tracker = trackers
+                    .entry(id.id)
+                    .or_insert_with(|| rx_tracker(id.id, &opts));
+
+                if tracker.total_bytes == 0 {
+                    waiting_since = None;
+                    tracker.src = src;
+                    tracker.restart_candidates = candidates;
+                    match src {
+                        Some(src) => {
+                            report_info(&opts, &format!("\nNew incoming traffic from {}:", src))
+                        }
+                        None => report_info(&opts, "\nNew incoming traffic:"),
+                    }
+                    if opts.setup_times {
+                        tracker.setup = Some(Setup {
+                            ready_secs: ready,
+                            first_frame_secs: since_start(),
+                        });
+                    }
+                    if id.probe {
+                        tracker.phase = Some(Phase::Probe);
+                    }
+                } else if tracker.phase == Some(Phase::Probe) && !id.probe {
+                    // The sender moved on to the sustained phase
+                    report_rx_done(tracker, &opts, wrong_ethertype);
+                    report_info(&opts, "\nSustained phase:");
+                    let idle_latency = tracker.latency();
+                    *tracker = rx_tracker(id.id, &opts);
+                    tracker.src = src;
+                    tracker.phase = Some(Phase::Sustained);
+                    tracker.idle_latency = idle_latency;
+                }
+
+                if let Some(off) = corrupt_at {
+                    if tracker.corrupt == 0 {
+                        clear_progress();
+                        eprintln!(
+                            "Corrupted payload in frame {} of stream {:x} at byte {}",
+                            id.cnt, id.id, off
+                        );
+                    }
+                    tracker.corrupt += 1;
+                }
+
+                if !pause.is_paused() {
+                    if let Some(report) = tracker.report_rx() {
+                        report_interval(&opts, report);
+                    }
+                }
+
+                if let Some(secs) = opts.rolling_summary {
+                    if tracker.last_rolling.elapsed().as_secs() >= secs {
+                        let mut report = tracker.report_rx_summary();
+                        report.kind = Kind::Rolling;
+                        report_summary(&opts, report);
+                        tracker.last_rolling = Instant::now();
+                    }
+                }
+
+                if opts.strict_sequence {
+                    check_sequence(tracker, &id)
+                        .map_err(|msg| Error::StrictSequence { stream: id.id, msg })?;
+                }
+
+                if id.last {
+                    if id.stamped {
+                        let latency_ns = unix_ns() as i128 - id.ts_ns as i128;
+                        tracker.final_frame = Some((id.cnt, latency_ns));
+                    }
+                    result = Some(report_rx_done(tracker, &opts, wrong_ethertype));
+                    trackers.remove(&id.id);
+                    closed.insert(id.id, (Instant::now(), 0));
+                    if tx_done.is_some() {
+                        return Ok(result);
+                    }
+                    continue;
+                }
+                if let Some(sent) = sent_len.filter(|&sent| sent > len) {
+                    if tracker.truncated.0 == 0 {
+                        clear_progress();
+                        eprintln!(
+                            "Truncated frame {} of stream {:x}: {} of {} payload bytes",
+                            id.cnt, id.id, len, sent
+                        );
+                    }
+                    tracker.truncated.0 += 1;
+                    tracker.truncated.1 += (sent - len) as u64;
+                }
+                let good = hot(Hot::Tracker, || tracker.insert(&id, len as u64));
+                if good && corrupt_at.is_some() {
+                    // Arrived in order but damaged, not goodput
+                    tracker.good_bytes -= (len as u64).saturating_sub(Id::size() as u64);
+                }
+                if opts.stream_ethertypes.is_some() {
+                    tracker.ethertype = tracker.ethertype.or(ethertype);
+                }
+                if let Some(ethertypes) = &mut tracker.ethertypes {
+                    let ethertype = ethertype.unwrap_or(opts.ethertype);
+                    ethertypes
+                        .entry(ethertype)
+                        .or_default()
+                        .record(id.cnt, len as u64);
+                }
+                if let Some(vlans) = &mut tracker.vlans {
+                    vlans.entry(vlan).or_default().record(id.cnt, len as u64);
+                }
+                if let (Some(payloads), Some(hash)) = (&mut tracker.payloads, hash) {
+                    payloads.record(hash);
+                }
+                let latency_us = if id.stamped {
+                    Some(tracker.record_latency(id.ts_ns))
+                } else {
+                    None
+                };
+                if opts.metrics_port.is_some() {
+                    if let Some(metrics) = METRICS.lock().unwrap().as_mut() {
+                        metrics.record(&id, len as u64, latency_us);
+                    }
+                }
+                if let Some(start) = proc_start {
+                    tracker.record_proc(start.elapsed());
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::NetworkDown) => {
+                clear_progress();
+                if grace.is_zero() {
+                    eprintln!("Link down, stopping");
+                    for t in trackers.values() {
+                        result = Some(report_rx_done(t, &opts, wrong_ethertype));
+                    }
+                    return Ok(result);
+                }
+                if down_since.is_none() {
+                    eprintln!("Link down, waiting up to {}s...", opts.link_grace);
+                    down_since = Some(Instant::now());
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut) => {
+                // Losing carrier doesn't raise an error on the socket
+                if down_since.is_none()
+                    && !grace.is_zero()
+                    && link_is_up(&opts.ifname) == Some(false)
+                {
+                    eprintln!("Link down, waiting up to {}s...", opts.link_grace);
+                    down_since = Some(Instant::now());
+                }
+                if let Some(since) = down_since {
+                    if since.elapsed() < grace {
+                        continue;
+                    }
+                    eprintln!("Link down for more than {}s, stopping", opts.link_grace);
+                    for t in trackers.values_mut() {
+                        t.outage += since.elapsed();
+                        result = Some(report_rx_done(t, &opts, wrong_ethertype));
+                    }
+                    return Ok(result);
+                }
+
+                // The streams were idle because of the pause, not finished
+                if pause.is_paused() || resumed.is_some() {
+                    continue;
+                }
+
+                if let (Some(since), Some(secs)) = (waiting_since, opts.startup_timeout) {
+                    if since.elapsed().as_secs() >= secs {
+                        waiting_since = None;
+                        startup_timed_out(&opts, secs, frame_no)?;
+                    }
+                }
+
+                // --strict-sequence: a stream that times out lost its final
+                // frame, which fails the run before any summary
+                if opts.strict_sequence {
+                    if let Some((&stream, t)) = trackers.iter().next() {
+                        let last = t.highest.map_or("none".to_string(), |s| s.to_string());
+                        return Err(Error::StrictSequence {
+                            stream,
+                            msg: format!("timed out without the final frame, last frame {}", last),
+                        });
+                    }
+                }
+                // Handle if the last packet was dropped
+                for (&stream, t) in &trackers {
+                    result = Some(report_rx_done(t, &opts, wrong_ethertype));
+                    closed.insert(stream, (Instant::now(), 0));
+                }
+                closed.retain(|_, (at, _)| at.elapsed() < CLOSED_GRACE);
+                if let Some(done) = &tx_done {
+                    if !trackers.is_empty() || done.load(Ordering::SeqCst) {
+                        return Ok(result);
+                    }
+                }
+                trackers.clear();
+            }
+            Err(e) => return Err(Error::Receive(e)),
+        }
+    }
+}
+
+type Channel = (
+    NetworkInterface,
+    Box<dyn datalink::DataLinkSender>,
+    Box<dyn datalink::DataLinkReceiver>,
+);
+
+// From linux/if_packet.h, missing in libc
+#[cfg(target_os = "linux")]
+const PACKET_QDISC_BYPASS: libc::c_int = 20;
+#[cfg(target_os = "linux")]
+const PACKET_AUXDATA: libc::c_int = 8;
+#[cfg(target_os = "linux")]
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+#[cfg(target_os = "linux")]
+const TP_STATUS_VLAN_TPID_VALID: u32 = 1 << 6;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct TpacketAuxdata {
+    tp_status: u32,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_vlan_tci: u16,
+    tp_vlan_tpid: u16,
+}
+
+// Frames read on our own AF_PACKET socket instead of through pnet's
+// receiver, on Linux only:
+// - Linux strips the outer VLAN tag of received frames (in the driver or when
+//   untagging in software) and only reports it through PACKET_AUXDATA, which
+//   pnet's recvfrom() drops. For --raw-capture the tag is put back in place, as
+//   libpcap does.
+// - With --rx-batch up to `slots` frames are read per recvmmsg() call and handed
+//   out one by one, into buffers sized for the interface MTU.
+#[cfg(target_os = "linux")]
+struct RawReceiver {
+    fd: libc::c_int,
+    _channel: Box<dyn datalink::DataLinkReceiver>, // Owns `fd`
+    restore_tags: bool,
+    bufs: Vec<Vec<u8>>, // Room for the tag is kept in front of each frame
+    controls: Vec<[u64; 8]>,
+    lens: Vec<usize>,
+    tags: Vec<Option<(u16, u16)>>,
+    filled: usize, // Slots read by the last recvmmsg()
+    next: usize,   // Next slot to hand out
+}
+
+#[cfg(target_os = "linux")]
+impl RawReceiver {
+    // `proto` is the ethertype of a Layer3 (SOCK_DGRAM) socket, None for raw
+    // capture
+    fn socket(proto: Option<u16>) -> io::Result<libc::c_int> {
+        let (kind, proto) = match proto {
+            Some(ethertype) => (libc::SOCK_DGRAM, ethertype),
+            None => (libc::SOCK_RAW, libc::ETH_P_ALL as u16),
+        };
+        let fd = unsafe { libc::socket(libc::AF_PACKET, kind, proto.to_be() as libc::c_int) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_PACKET,
+                PACKET_AUXDATA,
+                &on as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(fd)
+    }
+
+    fn new(
+        fd: libc::c_int,
+        channel: Box<dyn datalink::DataLinkReceiver>,
+        restore_tags: bool,
+        slots: usize,
+        frame_len: usize,
+    ) -> Self {
+        Self {
+            fd,
+            _channel: channel,
+            restore_tags,
+            bufs: vec![vec![0; frame_len + 4]; slots],
+            controls: vec![[0; 8]; slots],
+            lens: vec![0; slots],
+            tags: vec![None; slots],
+            filled: 0,
+            next: 0,
+        }
+    }
+
+    // The outer tag (TPID, TCI) reported for a received frame
+    fn tag(msg: &libc::msghdr) -> Option<(u16, u16)> {
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+        while !cmsg.is_null() {
+            let hdr = unsafe { &*cmsg };
+            if hdr.cmsg_level == libc::SOL_PACKET && hdr.cmsg_type == PACKET_AUXDATA {
+                let aux =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const TpacketAuxdata).read_unaligned() };
+                if aux.tp_status & TP_STATUS_VLAN_VALID == 0 {
+                    return None;
+                }
+                let tpid = if aux.tp_status & TP_STATUS_VLAN_TPID_VALID != 0 {
+                    aux.tp_vlan_tpid
+                } else {
+                    ETHERTYPE_VLAN
+                };
+                return Some((tpid, aux.tp_vlan_tci));
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+        }
+        None
+    }
+
+    // Wait for frames and read as many as are queued, up to one per slot.
+    // The socket is non-blocking (pnet sets it up so), so recvmmsg() returns
+    // what's there instead of waiting for all slots.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = RX_TIMEOUT.as_millis() as libc::c_int;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::new(ErrorKind::TimedOut, "Timed out")),
+            _ => {}
+        }
+
+        let mut iovs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf[4..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len() - 4,
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(self.controls.iter_mut())
+            .map(|(iov, control)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_hdr.msg_controllen = mem::size_of_val(control) as _;
+                msg
+            })
+            .collect();
+        let n = unsafe {
+            libc::recvmmsg(
+                self.fd,
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for (i, msg) in msgs[..n as usize].iter().enumerate() {
+            self.lens[i] = msg.msg_len as usize;
+            self.tags[i] = Self::tag(&msg.msg_hdr);
+        }
+        self.filled = n as usize;
+        self.next = 0;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl datalink::DataLinkReceiver for RawReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        if self.next == self.filled {
+            self.fill()?;
+        }
+        let slot = self.next;
+        self.next += 1;
+
+        let len = self.lens[slot];
+        let buf = &mut self.bufs[slot];
+        match self.tags[slot] {
+            Some((tpid, tci)) if self.restore_tags && len >= 12 => {
+                buf.copy_within(4..16, 0); // SRC + DST
+                buf[12..14].copy_from_slice(&tpid.to_be_bytes());
+                buf[14..16].copy_from_slice(&tci.to_be_bytes());
+                Ok(&buf[..len + 4])
+            }
+            _ => Ok(&buf[4..len + 4]),
+        }
+    }
+}
+
+// Largest frame the interface can deliver to --rx-batch buffers, headers and
+// one VLAN tag included. Without a readable MTU, the largest possible one.
+#[cfg(target_os = "linux")]
+fn rx_frame_len(ifname: &str) -> usize {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", ifname))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse::<usize>().ok())
+        .map_or(65536, |mtu| mtu + ETH_HEADER_SIZE + 4)
+}
+
+fn tx_queue_count(ifname: &str) -> Option<usize> {
+    let dir = std::fs::read_dir(format!("/sys/class/net/{}/queues", ifname)).ok()?;
+    Some(
+        dir.filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with("tx-"))
+            .count(),
+    )
+}
+
+// Pre-create the TX socket so its options are in place before pnet binds it.
+// SO_PRIORITY sets skb->priority, which mqprio/prio qdiscs and tc filters map
+// to a queue. With PACKET_QDISC_BYPASS the kernel instead picks the TX queue
+// from the sending CPU (cpu % real_num_tx_queues, unless XPS or the driver
+// overrides it), so --tx-queue also pins the TX thread to that CPU.
+// SO_NOFCS asks the driver not to append the FCS, for --bad-fcs.
+#[cfg(target_os = "linux")]
+fn tx_socket(opts: &Opt, ifname: &str) -> Result<Option<i32>, Error> {
+    if opts.tx_priority.is_none() && opts.tx_queue.is_none() && opts.bad_fcs.is_none() {
+        return Ok(None);
+    }
+
+    if let (Some(queue), Some(count)) = (opts.tx_queue, tx_queue_count(ifname)) {
+        if queue >= count {
+            return Err(Error::Config(format!(
+                "{} has only {} TX queues",
+                ifname, count
+            )));
+        }
+    }
+
+    let proto = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, proto) };
+    if fd == -1 {
+        return Err(Error::channel(ifname, io::Error::last_os_error()));
+    }
+
+    let set = |level, name, val: libc::c_int, what| {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &val as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(Error::Socket { what, source: err });
+        }
+        Ok(())
+    };
+
+    if let Some(prio) = opts.tx_priority {
+        set(
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            prio as libc::c_int,
+            "SO_PRIORITY",
+        )?;
+    }
+    if opts.tx_queue.is_some() {
+        set(
+            libc::SOL_PACKET,
+            PACKET_QDISC_BYPASS,
+            1,
+            "PACKET_QDISC_BYPASS",
+        )?;
+    }
+    if opts.bad_fcs.is_some() {
+        set(libc::SOL_SOCKET, libc::SO_NOFCS, 1, "SO_NOFCS")?;
+    }
+
+    Ok(Some(fd))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tx_socket(opts: &Opt, _ifname: &str) -> Result<Option<i32>, Error> {
+    if opts.tx_priority.is_some() || opts.tx_queue.is_some() {
+        eprintln!("TX queue selection is only supported on Linux, ignoring");
+    }
+    if opts.bad_fcs.is_some() {
+        return Err(Error::Config(
+            "--bad-fcs is only supported on Linux".to_string(),
+        ));
+    }
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::from(ErrorKind::Unsupported))
+}
+
+// --rt-priority, whether the TX thread got it
+#[derive(Debug, Clone, Copy, Serialize)]
+struct RtPriority {
+    priority: u8,
+    granted: bool,
+}
+
+// SCHED_FIFO for the calling thread only, so the RX thread of a loopback test
+// and the main thread keep normal scheduling. Fails with EPERM without
+// CAP_SYS_NICE or an RLIMIT_RTPRIO that allows `priority`.
+#[cfg(target_os = "linux")]
+fn set_rt_priority(priority: u8) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority as libc::c_int,
+    };
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_rt_priority(_priority: u8) -> io::Result<()> {
+    Err(io::Error::from(ErrorKind::Unsupported))
+}
+
+// IRQ numbers in /proc/interrupts whose action names belong to `ifname`,
+// e.g. "eth0", "eth0-rx-0" or "eth0-TxRx-3"
+#[cfg(target_os = "linux")]
+fn interface_irqs(ifname: &str) -> Vec<u32> {
+    let interrupts = std::fs::read_to_string("/proc/interrupts").unwrap_or_default();
+    let prefix = format!("{}-", ifname);
+    interrupts
+        .lines()
+        .filter_map(|line| {
+            let (irq, rest) = line.split_once(':')?;
+            let irq = irq.trim().parse().ok()?;
+            rest.split(|c: char| c.is_whitespace() || c == ',')
+                .any(|name| name == ifname || name.starts_with(&prefix))
+                .then_some(irq)
+        })
+        .collect()
+}
+
+// Move the interface's IRQs to `cpu`, printing what to run instead where it
+// isn't permitted
+#[cfg(target_os = "linux")]
+fn steer_irqs(opts: &Opt, cpu: usize) {
+    let irqs = interface_irqs(&opts.ifname);
+    if irqs.is_empty() {
+        report_info(
+            opts,
+            &format!("No IRQs of {} found in /proc/interrupts", opts.ifname),
+        );
+        return;
+    }
+
+    for irq in irqs {
+        let path = format!("/proc/irq/{}/smp_affinity_list", irq);
+        match std::fs::write(&path, cpu.to_string()) {
+            Ok(()) => report_info(opts, &format!("IRQ {}: moved to CPU {}", irq, cpu)),
+            Err(e) => eprintln!(
+                "IRQ {}: can't set affinity ({}), try: echo {} > {}",
+                irq, e, cpu, path
+            ),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn steer_irqs(_opts: &Opt, _cpu: usize) {
+    eprintln!("IRQ steering is only supported on Linux, ignoring");
+}
+
+// SIGUSR1 pauses a running test and SIGUSR2 resumes it, see Pause
+#[cfg(unix)]
+fn install_pause_signals() {
+    extern "C" fn on_signal(sig: libc::c_int) {
+        // Only atomics and the clock, both fine in a signal handler
+        let now = START.get().map_or(0, |s| s.elapsed().as_nanos() as u64);
+        if sig == libc::SIGUSR1 {
+            if !PAUSED.load(Ordering::SeqCst) {
+                PAUSED_AT_NS.store(now, Ordering::SeqCst);
+                PAUSED.store(true, Ordering::SeqCst);
+            }
+        } else if PAUSED.swap(false, Ordering::SeqCst) {
+            let since = PAUSED_AT_NS.load(Ordering::SeqCst);
+            PAUSED_NS.fetch_add(now.saturating_sub(since), Ordering::SeqCst);
+        }
+    }
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+        libc::signal(libc::SIGUSR2, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_pause_signals() {}
+
+// Follows PAUSED for one TX or RX loop. A paused sender sends nothing, not
+// even keepalives, and its test runs longer by the pause. A paused receiver
+// keeps reading and counting frames, so none are lost to the pause, but it
+// neither prints intervals nor times out idle streams. Both move their clocks
+// past the pause on resume.
+struct Pause {
+    paused: bool,
+    seen_ns: u64, // Of PAUSED_NS, already accounted for
+    what: &'static str,
+}
+
+impl Pause {
+    fn new(what: &'static str) -> Self {
+        Self {
+            paused: false,
+            seen_ns: PAUSED_NS.load(Ordering::SeqCst),
+            what,
+        }
+    }
+
+    // The length of pauses that ended since the last call
+    fn poll(&mut self) -> Option<Duration> {
+        let paused = PAUSED.load(Ordering::SeqCst);
+        if paused && !self.paused {
+            clear_progress();
+            eprintln!("{} paused, SIGUSR2 resumes", self.what);
+        }
+        self.paused = paused;
+
+        let total = PAUSED_NS.load(Ordering::SeqCst);
+        if total == self.seen_ns {
+            return None;
+        }
+        let resumed = Duration::from_nanos(total - self.seen_ns);
+        self.seen_ns = total;
+        clear_progress();
+        eprintln!("{} resumed after {:.2}s", self.what, resumed.as_secs_f32());
+        Some(resumed)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
+
+// Whether the process holds CAP_NET_RAW, None if it can't be told
+#[cfg(target_os = "linux")]
+fn has_net_raw() -> Option<bool> {
+    const CAP_NET_RAW: u32 = 13;
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let caps = status.lines().find_map(|l| l.strip_prefix("CapEff:"))?;
+    let caps = u64::from_str_radix(caps.trim(), 16).ok()?;
+    Some(caps & (1 << CAP_NET_RAW) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_net_raw() -> Option<bool> {
+    None
+}
+
+// The source MAC of frames sent on `iface`, which --dst-self also sends to
+fn source_mac(iface: &NetworkInterface, opts: &Opt) -> Result<MacAddr, Error> {
+    iface
+        .mac
+        .filter(|mac| !opts.dst_self || *mac != MacAddr::zero())
+        .ok_or_else(|| {
+            let hint = if opts.dst_self {
+                ", --dst-self needs one"
+            } else {
+                ""
+            };
+            Error::Config(format!("{} has no MAC address{}", iface.name, hint))
+        })
+}
+
+// Every interface a test sends on needs a source MAC, checked before any
+// channel or thread is opened. Missing interfaces are left to open_channel.
+fn check_source_macs(opts: &Opt) -> Result<(), Error> {
+    let ifnames = match (&opts.tx_ifname, &opts.rx_ifname) {
+        (Some(tx), Some(rx)) if opts.bidir => vec![tx, rx],
+        (Some(tx), Some(_)) => vec![tx],
+        _ => vec![&opts.ifname],
+    };
+    let interfaces = datalink::interfaces();
+    for ifname in ifnames {
+        if let Some(iface) = interfaces.iter().find(|iface| &iface.name == ifname) {
+            source_mac(iface, opts)?;
+        }
+    }
+    Ok(())
+}
+
+fn open_channel(ifname: &str, rx_ethertype: Option<u16>, opts: &Opt) -> Result<Channel, Error> {
+    let interfaces = datalink::interfaces();
+    let interface = interfaces
+        .iter()
+        .find(|iface| iface.name == ifname)
+        .cloned()
+        .ok_or_else(|| Error::interface_not_found(ifname, &interfaces))?;
+
+    let mut config: Config = Default::default();
+
+    if let Some(ethertype) = rx_ethertype {
+        config.channel_type = if opts.raw_capture {
+            ChannelType::Layer2
+        } else {
+            ChannelType::Layer3(ethertype)
+        };
+        config.read_timeout = Some(RX_TIMEOUT);
+        #[cfg(target_os = "linux")]
+        if opts.raw_capture || opts.rx_batch > 1 {
+            let proto = Some(ethertype).filter(|_| !opts.raw_capture);
+            config.socket_fd =
+                Some(RawReceiver::socket(proto).map_err(|e| Error::channel(ifname, e))?);
+        }
+        if opts.rx_threads > 1 {
+            // One group per process, the kernel hands each frame to the
+            // thread whose index matches the CPU that received it
+            config.linux_fanout = Some(FanoutOption {
+                group_id: process::id() as u16,
+                fanout_type: FanoutType::CPU,
+                defrag: false,
+                rollover: false,
+            });
+        }
+    } else {
+        config.socket_fd = tx_socket(opts, ifname)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    let raw_fd = config.socket_fd.filter(|_| rx_ethertype.is_some());
+    match datalink::channel(&interface, config) {
+        #[cfg(target_os = "linux")]
+        Ok(Ethernet(tx, rx)) if raw_fd.is_some() => {
+            let frame_len = if opts.rx_batch > 1 {
+                rx_frame_len(ifname)
+            } else {
+                65536
+            };
+            let rx = Box::new(RawReceiver::new(
+                raw_fd.unwrap(),
+                rx,
+                opts.raw_capture,
+                opts.rx_batch,
+                frame_len,
+            ));
+            Ok((interface, tx, rx))
+        }
+        Ok(Ethernet(tx, rx)) => Ok((interface, tx, rx)),
+        Ok(_) => Err(Error::channel(
+            ifname,
+            io::Error::other("Unhandled channel type"),
+        )),
+        Err(e) => Err(Error::channel(ifname, e)),
+    }
+}
+
+type Direction = (
+    NetworkInterface,
+    Box<dyn datalink::DataLinkSender>,
+    NetworkInterface,
+    Box<dyn datalink::DataLinkReceiver>,
+);
+
+fn open_direction(opt: &Opt, tx_ifname: &str, rx_ifname: &str) -> Result<Direction, Error> {
+    let (tx_iface, tx, _) = open_channel(tx_ifname, None, opt)?;
+    let (rx_iface, _, rx) = open_channel(rx_ifname, Some(opt.ethertype), opt)?;
+    Ok((tx_iface, tx, rx_iface, rx))
+}
+
+// --mtu-probe: each payload size is sent this many times, so a size only
+// fails if all of them are lost
+const MTU_PROBE_TRIES: usize = 3;
+
+// How long a probe waits for its frames after the last one was sent
+const MTU_PROBE_WAIT: Duration = Duration::from_millis(200);
+
+// Payload size past the test's first probed, with room for a VLAN tag
+const MTU_PROBE_MARGIN: usize = 4;
+
+// Send a direction's frames a little larger than the payload size before the
+// test, and if they don't arrive bisect down to the largest payload that
+// does: the path's effective MTU. The frames are training frames, TRAIN_MAGIC
+// and a token, so the test's receiver drops any late ones unseen.
+fn mtu_probe(opt: &Opt, dir: &mut Direction) {
+    let (tx_iface, tx, rx_iface, _) = dir;
+    let config = Config {
+        read_timeout: Some(MTU_PROBE_WAIT),
+        ..Default::default()
+    };
+    let mut rx = match datalink::channel(rx_iface, config) {
+        Ok(Ethernet(_, rx)) => rx,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!(
+                "MTU probe: can't open {}: {}, skipping it",
+                rx_iface.name, e
+            );
+            return;
+        }
+    };
+    let path = format!("{} -> {}", tx_iface.name, rx_iface.name);
+    let token: u64 = rand::thread_rng().gen();
+    let dest = opt.dest.or(rx_iface.mac).unwrap_or(MacAddr::broadcast());
+    let src = tx_iface.mac.unwrap_or(MacAddr::zero());
+
+    let mut passes = |psize: usize| {
+        let mut frame = vec![0; ETH_HEADER_SIZE + psize];
+        let mut packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        packet.set_destination(dest);
+        packet.set_source(src);
+        packet.set_ethertype(EtherType::new(opt.ethertype));
+        let mut tag = TRAIN_MAGIC.to_vec();
+        tag.extend_from_slice(&token.to_le_bytes());
+        tag.extend_from_slice(&(psize as u32).to_le_bytes());
+        frame[ETH_HEADER_SIZE..ETH_HEADER_SIZE + tag.len()].copy_from_slice(&tag);
+        for _ in 0..MTU_PROBE_TRIES {
+            // Larger than the sending interface's MTU, it can't pass
+            if !matches!(tx.send_to(&frame, None), Some(Ok(()))) {
+                return false;
+            }
+        }
+        let deadline = Instant::now() + MTU_PROBE_WAIT;
+        while Instant::now() < deadline {
+            match rx.next() {
+                Ok(got) if got.get(ETH_HEADER_SIZE..ETH_HEADER_SIZE + tag.len()) == Some(&tag) => {
+                    return true
+                }
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        false
+    };
+
+    let probed = opt.psize + MTU_PROBE_MARGIN;
+    if passes(probed) {
+        report_info(
+            opt,
+            &format!("MTU probe {}: {} byte payloads get through", path, probed),
+        );
+        return;
+    }
+    let (mut lo, mut hi) = (MIN_PAYLOAD as usize, probed);
+    if !passes(lo) {
+        // Frames of any size are lost, that's no MTU limit
+        eprintln!(
+            "MTU probe {}: not even {} byte payloads arrived, can't tell the MTU",
+            path, lo
+        );
+        return;
+    }
+    // lo gets through, hi doesn't
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if passes(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == opt.psize {
+        report_info(
+            opt,
+            &format!(
+                "MTU probe {}: the path's MTU is {}, no room above the payload size",
+                path, lo
+            ),
+        );
+    } else if lo > opt.psize {
+        report_info(
+            opt,
+            &format!(
+                "MTU probe {}: the path's MTU is {}, {} bytes above the payload size",
+                path,
+                lo,
+                lo - opt.psize
+            ),
+        );
+    } else {
+        clear_progress();
+        eprintln!(
+            "Warning: MTU probe {}: the path's MTU is {}, {} byte payloads won't get through",
+            path, lo, opt.psize
+        );
+    }
+}
+
+// --timestamp-every for --inject-delay without one, so the delay shows in the
+// latency
+const INJECT_STAMP_EVERY: u64 = 10;
+
+// --inject-delay: frames handed to `send_to` are queued and sent by a thread
+// once they are `delay` old, in order. Dropping the sender sends what's still
+// queued first, so the sentinel isn't lost. Send errors can't reach the caller
+// and are only printed.
+struct DelayedSender {
+    queue: Option<mpsc::Sender<(Instant, Vec<u8>)>>,
+    thread: Option<thread::JoinHandle<()>>,
+    delay: Duration,
+}
+
+impl DelayedSender {
+    fn new(mut tx: Box<dyn datalink::DataLinkSender>, delay: Duration) -> Self {
+        let (queue, frames) = mpsc::channel::<(Instant, Vec<u8>)>();
+        let thread = thread::spawn(move || {
+            let mut errors = 0;
+            for (due, frame) in frames {
+                wait_until(due);
+                if let Some(Err(e)) = tx.send_to(&frame, None) {
+                    if errors == 0 {
+                        clear_progress();
+                        eprintln!("Failed to send a delayed frame: {}", e);
+                    }
+                    errors += 1;
+                }
+            }
+        });
+        Self {
+            queue: Some(queue),
+            thread: Some(thread),
+            delay,
+        }
+    }
+}
+
+impl datalink::DataLinkSender for DelayedSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0; packet_size];
+            func(&mut frame);
+            self.send_to(&frame, None)?.ok()?;
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        let queue = self.queue.as_ref()?;
+        queue
+            .send((Instant::now() + self.delay, packet.to_vec()))
+            .ok()?;
+        Some(Ok(()))
+    }
+}
+
+impl Drop for DelayedSender {
+    fn drop(&mut self) {
+        self.queue = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+// A direction running, see run_direction
+type RxHandle = thread::JoinHandle<Result<Option<RunResult>, Error>>;
+
+// Runs one direction on its own TX and RX threads, returning the result of
+// the received stream
+fn run_direction(mut opt: Opt, dir: Direction) -> Result<RxHandle, Error> {
+    let (tx_iface, mut tx, rx_iface, mut rx) = dir;
+    if let Some(ms) = opt.inject_delay {
+        tx = Box::new(DelayedSender::new(tx, Duration::from_secs_f64(ms / 1e3)));
+    }
+    if opt.dest.is_none() {
+        opt.dest = rx_iface.mac;
+    }
+
+    let mac = source_mac(&tx_iface, &opt)?;
+    Ok(thread::spawn(move || {
+        let tx_done = Arc::new(AtomicBool::new(false));
+
+        let mut rx_opt = opt.clone();
+        rx_opt.ifname = rx_iface.name.clone();
+        let rx_done = tx_done.clone();
+        let rx_thread = thread::spawn(move || rx_traffic(&mut rx, rx_opt, Some(rx_done)));
+
+        opt.ifname = tx_iface.name.clone();
+        let tx_thread = thread::spawn(move || tx_traffic(&mut tx, mac, opt));
+
+        let tx_res = tx_thread.join();
+        tx_done.store(true, Ordering::SeqCst);
+        let rx_res = rx_thread.join();
+
+        match (tx_res, rx_res) {
+            (Ok(tx_res), Ok(rx_res)) => {
+                tx_res?;
+                rx_res
+            }
+            _ => Err(Error::Thread("Loopback test")),
+        }
+    }))
+}
+
+// Returns the forward direction's RX result
+fn loopback(opt: Opt, tx_ifname: &str, rx_ifname: &str) -> Result<Option<RunResult>, Error> {
+    // Open all sides before starting any traffic so a failure on either
+    // one leaves nothing running
+    let mut forward = open_direction(&opt, tx_ifname, rx_ifname)?;
+    let reverse_opt = reverse_opts(&opt);
+    let mut reverse = if opt.bidir {
+        Some(open_direction(&reverse_opt, rx_ifname, tx_ifname)?)
+    } else {
+        None
+    };
+    if opt.mtu_probe {
+        mtu_probe(&opt, &mut forward);
+        if let Some(reverse) = &mut reverse {
+            mtu_probe(&reverse_opt, reverse);
+        }
+    }
+
+    let forward = run_direction(opt.clone(), forward)?;
+    let reverse = reverse
+        .map(|dir| run_direction(reverse_opt.clone(), dir))
+        .transpose()?;
+
+    let join = |h: RxHandle| h.join().unwrap_or(Err(Error::Thread("Loopback test")));
+    let forward = join(forward)?;
+    let reverse = match reverse {
+        Some(r) => join(r)?,
+        None => return Ok(forward),
+    };
+
+    let dropped = |r: Option<RunResult>| r.and_then(|r| Some((r.rate_bps, r.dropped_pct?)));
+    let latency = |r: Option<RunResult>| r.and_then(|r| r.latency);
+    match (dropped(forward), dropped(reverse)) {
+        (Some((fwd_rate, fwd)), Some((rev_rate, rev))) => {
+            let asymmetry_pct = (fwd - rev).abs();
+            let asymmetry = Asymmetry {
+                kind: "asymmetry",
+                forward: format!("{} -> {}", tx_ifname, rx_ifname),
+                reverse: format!("{} -> {}", rx_ifname, tx_ifname),
+                forward_psize: opt.psize,
+                reverse_psize: reverse_opt.psize,
+                forward_rate_bps: fwd_rate,
+                reverse_rate_bps: rev_rate,
+                unit: opt.units.pick(fwd_rate.max(rev_rate)),
+                forward_dropped_pct: fwd,
+                reverse_dropped_pct: rev,
+                asymmetry_pct,
+                asymmetric: asymmetry_pct > opt.asymmetry_threshold,
+                forward_latency: latency(forward),
+                reverse_latency: latency(reverse),
+            };
+            clear_progress();
+            print_report(&opt, &asymmetry);
+        }
+        _ => eprintln!("Missing a direction's summary, can't compare drop rates"),
+    }
+    Ok(forward)
+}
+
+// --rx-threads: a receiver per CPU, with per-thread totals printed whenever
+// the traffic pauses
+fn rx_threads(opt: Opt) -> Result<(), Error> {
+    let channels = (0..opt.rx_threads)
+        .map(|_| open_channel(&opt.ifname, Some(opt.ethertype), &opt).map(|(_, _, rx)| rx))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let (results_tx, results) = mpsc::channel();
+    *RX_RESULTS.lock().unwrap() = Some(results_tx);
+    // A thread that fails hands its error over, to end the run from here
+    let (errors_tx, errors) = mpsc::channel();
+
+    for (cpu, mut rx) in channels.into_iter().enumerate() {
+        let mut opt = opt.clone();
+        opt.rx_thread = Some(cpu);
+        let errors_tx = errors_tx.clone();
+        thread::spawn(move || {
+            if let Err(e) = pin_to_cpu(cpu) {
+                eprintln!("Failed to pin RX thread {} to CPU {}: {}", cpu, cpu, e);
+            }
+            if let Err(e) = rx_traffic(&mut rx, opt, None) {
+                errors_tx.send(e).ok();
+            }
+        });
+    }
+
+    let mut batch = vec![];
+    loop {
+        if let Ok(e) = errors.try_recv() {
+            return Err(e);
+        }
+        match results.recv_timeout(2 * RX_TIMEOUT) {
+            Ok(result) => batch.push(result),
+            Err(RecvTimeoutError::Timeout) if !batch.is_empty() => {
+                clear_progress();
+                print_report(&opt, &ThreadTotals::new(opt.rx_threads, &batch));
+                batch.clear();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+const SELFTEST_FRAMES: u64 = 100_000;
+
+// --selftest: run a generated stream with known faults through a Tracker, as
+// the RX path would, and compare what it reports with what was injected.
+// Returns whether everything matched.
+fn selftest(opts: &Opt) -> bool {
+    let rates = opts.selftest_rates;
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+
+    let mut arrivals = vec![];
+    let mut lost = 0;
+    let mut dups = 0;
+    // From --start-seq, so a start close to the top checks the accounting
+    // across the wrap
+    for seq in (0..SELFTEST_FRAMES).map(|n| opts.start_seq.wrapping_add(n)) {
+        if rng.gen_bool(rates.loss) {
+            lost += 1;
+            continue;
+        }
+        arrivals.push(seq);
+        if rng.gen_bool(rates.dup) {
+            arrivals.push(seq);
+            dups += 1;
+        }
+    }
+    // Swap neighbours, the first and last frames included
+    let mut swaps = 0;
+    let mut i = 0;
+    while i + 1 < arrivals.len() {
+        if arrivals[i] != arrivals[i + 1] && rng.gen_bool(rates.reorder) {
+            arrivals.swap(i, i + 1);
+            swaps += 1;
+            i += 1;
+        }
+        i += 1;
+    }
+
+    let mut tracker = rx_tracker(0x5e1f_7e57, opts);
+    let mut id = Id::new(tracker.stream, opts.start_seq);
+    for &seq in &arrivals {
+        id.cnt = seq;
+        tracker.insert(&id, opts.psize as u64);
+    }
+    let report = tracker.report_rx_summary();
+
+    let injected_pct = lost as f32 / SELFTEST_FRAMES as f32 * 100.0;
+    let measured_pct = report.dropped_pct.unwrap_or(0.0);
+    // Lost frames at either end fall outside the span the receiver sees
+    let tolerance = 2.0 / SELFTEST_FRAMES as f32 * 100.0;
+    // All frames have the same size, so duplicates take their share of the rate
+    let unique_pct = (arrivals.len() as u64 - dups) as f32 / arrivals.len() as f32 * 100.0;
+    let measured_unique_pct = tracker.unique_rate(report.rate_bps) / report.rate_bps * 100.0;
+    let checks = [
+        (
+            "Lost",
+            format!("{:.3}%", injected_pct),
+            format!("{:.3}%", measured_pct),
+            (injected_pct - measured_pct).abs() <= tolerance,
+        ),
+        (
+            "Duplicates",
+            dups.to_string(),
+            report.duplicates.unwrap_or(0).to_string(),
+            report.duplicates.unwrap_or(0) == dups,
+        ),
+        (
+            "Unique rate",
+            format!("{:.3}% of the rate", unique_pct),
+            format!("{:.3}% of the rate", measured_unique_pct),
+            (unique_pct - measured_unique_pct).abs() <= 0.001,
+        ),
+        (
+            "Out of order",
+            swaps.to_string(),
+            report.out_of_order.unwrap_or(0).to_string(),
+            report.out_of_order.unwrap_or(0) == swaps,
+        ),
+    ];
+
+    println!(
+        "Self-test: {} frames, seed {}, loss {}, duplicates {}, reorder {}",
+        SELFTEST_FRAMES, opts.seed, rates.loss, rates.dup, rates.reorder
+    );
+    let mut ok = true;
+    for (what, injected, measured, pass) in &checks {
+        println!(
+            "{}: injected {}, measured {}: {}",
+            what,
+            injected,
+            measured,
+            if *pass { "ok" } else { "MISMATCH" }
+        );
+        ok &= pass;
+    }
+    ok
+}
+
+// --show-config: the parsed options after defaults and unit suffixes, plus
+// the values derived from them. Only looks up interfaces, which needs no
+// privileges.
+fn show_config(opts: &Opt) {
+    let mac_of = |ifname: &str| {
+        datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == ifname)
+            .map_or("interface not found".to_string(), |iface| {
+                iface
+                    .mac
+                    .map_or("no MAC".to_string(), |mac| mac.to_string())
+            })
+    };
+
+    let mut lines = vec![format!("Resolved options: {:#?}", opts)];
+    let ifnames: Vec<&str> = match (&opts.tx_ifname, &opts.rx_ifname) {
+        (Some(tx), Some(rx)) => vec![tx, rx],
+        _ => vec![&opts.ifname],
+    };
+    for ifname in ifnames {
+        lines.push(format!("Interface {}: {}", ifname, mac_of(ifname)));
+    }
+    let dest = match (&opts.rx_ifname, opts.dest) {
+        _ if opts.dst_self => Some(format!("{} (own MAC)", mac_of(&opts.ifname))),
+        (_, Some(dest)) => Some(dest.to_string()),
+        (Some(rx), None) => Some(format!("{} ({})", mac_of(rx), rx)),
+        (None, None) => None,
+    };
+    if let Some(dest) = dest.filter(|_| !opts.rx) {
+        lines.push(format!("Destination: {}", dest));
+    }
+    lines.push(format!(
+        "Frame size: {} bytes ({} header + {} payload, without FCS)",
+        opts.psize + ETH_HEADER_SIZE,
+        ETH_HEADER_SIZE,
+        opts.psize
+    ));
+    lines.push(format!(
+        "Id header: {} bytes at payload offset {}",
+        head_size(opts),
+        id_offset(opts)
+    ));
+    if let Some(prefix) = &opts.payload_prefix {
+        lines.push(format!(
+            "Payload prefix: {} bytes at payload offset {}",
+            prefix.0.len(),
+            prefix_offset(opts)
+        ));
+    }
+    lines.push(format!(
+        "Ether Type: {:x}{}",
+        opts.ethertype,
+        ethertype_label(opts.ethertype)
+    ));
+    report_info(opts, &lines.join("\n"));
 }
 
-fn parse_hex(src: &str) -> Result<u16, ParseIntError> {
-    u16::from_str_radix(src, 16)
+// Stream count and CPU count for --parallel. Explicit counts above the CPU
+// count are honored but share cores, auto stops at MAX_PARALLEL.
+fn parallel_streams(parallel: Parallel) -> (usize, usize) {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    match parallel {
+        Parallel::Auto => (cpus.min(MAX_PARALLEL), cpus),
+        Parallel::Count(n) => {
+            if n > cpus {
+                eprintln!(
+                    "Warning: {} streams on {} CPUs, streams will share cores",
+                    n, cpus
+                );
+            }
+            (n, cpus)
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Id {
-    id: u32,
-    cnt: u64,
-    last: bool,
+// Run --parallel senders, each on its own channel and CPU, and combine their
+// results into one
+fn tx_parallel(opt: &Opt, parallel: Parallel) -> Result<RunResult, Error> {
+    let (streams, cpus) = parallel_streams(parallel);
+    let cpu_of = |stream: usize| stream % cpus;
+    let ethertype_of = |stream: usize| {
+        opt.stream_ethertypes
+            .as_ref()
+            .map_or(opt.ethertype, |l| l.0[stream])
+    };
+    if let Some(list) = opt
+        .stream_ethertypes
+        .as_ref()
+        .filter(|l| l.0.len() != streams)
+    {
+        return Err(Error::Config(format!(
+            "--stream-ethertypes lists {} ethertypes for {} streams (--parallel auto)",
+            list.0.len(),
+            streams
+        )));
+    }
+    let assignment: Vec<String> = (0..streams)
+        .map(|s| format!("{}->CPU {}", s, cpu_of(s)))
+        .collect();
+    report_info(
+        opt,
+        &format!("Parallel streams: {} ({})", streams, assignment.join(", ")),
+    );
+    // Drawn up front from the seeded RNG, so a seed always staggers the same
+    let mut rng = StdRng::seed_from_u64(opt.seed);
+    let delays: Vec<Duration> = (0..streams)
+        .map(|_| match opt.stagger {
+            Some(max) => max.mul_f64(rng.gen()),
+            None => Duration::ZERO,
+        })
+        .collect();
+    if let Some(max) = opt.stagger {
+        let delays: Vec<String> = delays
+            .iter()
+            .enumerate()
+            .map(|(s, d)| format!("{}: {:.2} ms", s, d.as_secs_f64() * 1e3))
+            .collect();
+        report_info(
+            opt,
+            &format!(
+                "Stagger: up to {:.2} ms, seed {} ({})",
+                max.as_secs_f64() * 1e3,
+                opt.seed,
+                delays.join(", ")
+            ),
+        );
+    }
+
+    let handles = (0..streams)
+        .map(|stream| {
+            let (interface, mut tx, _) = open_channel(&opt.ifname, None, opt)?;
+            let mut opt = opt.clone();
+            opt.tx_stream = Some(stream);
+            opt.start_delay = delays[stream];
+            opt.bandwidth /= streams as f32;
+            opt.ethertype = ethertype_of(stream);
+            let cpu = cpu_of(stream);
+            let mac = source_mac(&interface, &opt)?;
+            Ok(thread::spawn(move || {
+                if let Err(e) = pin_to_cpu(cpu) {
+                    eprintln!("Failed to pin TX stream {} to CPU {}: {}", stream, cpu, e);
+                }
+                tx_traffic(&mut tx, mac, opt)
+            }))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let results = handles
+        .into_iter()
+        .map(|h| h.join().unwrap_or(Err(Error::Thread("TX stream"))))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let total_rate_bps = results.iter().fold(0.0, |sum, r| sum + r.rate_bps);
+    let totals = ParallelTotals {
+        kind: "parallel",
+        streams: results
+            .iter()
+            .enumerate()
+            .map(|(stream, r)| StreamStat {
+                stream,
+                cpu: cpu_of(stream),
+                stagger_ms: opt.stagger.map(|_| delays[stream].as_secs_f64() * 1e3),
+                ethertype: opt.stream_ethertypes.as_ref().map(|_| ethertype_of(stream)),
+                pkts: r.pkts,
+                rate_bps: r.rate_bps,
+            })
+            .collect(),
+        total_pkts: results.iter().map(|r| r.pkts).sum(),
+        total_rate_bps,
+        unit: opt.units.pick(total_rate_bps),
+    };
+    clear_progress();
+    print_report(opt, &totals);
+
+    Ok(RunResult {
+        rate_bps: total_rate_bps,
+        dropped_pct: None,
+        pkts: totals.total_pkts,
+        secs: results.iter().fold(0.0, |max, r| r.secs.max(max)),
+        latency: None,
+    })
 }
 
-impl Id {
-    pub fn new(id: u32) -> Self {
-        Self {
-            id,
-            cnt: 0,
-            last: false,
+// One TX or loopback test with the current options
+fn run_test(opt: &Opt) -> Result<Option<RunResult>, Error> {
+    if let (Some(tx_ifname), Some(rx_ifname)) = (&opt.tx_ifname, &opt.rx_ifname) {
+        loopback(opt.clone(), tx_ifname, rx_ifname)
+    } else if let Some(parallel) = opt.parallel {
+        tx_parallel(opt, parallel).map(Some)
+    } else {
+        let (interface, mut tx, _) = open_channel(&opt.ifname, None, opt)?;
+        let mac = source_mac(&interface, opt)?;
+        tx_traffic(&mut tx, mac, opt.clone()).map(Some)
+    }
+}
+
+fn size_sweep(mut opt: Opt, sweep: SizeSweep) -> Result<(), Error> {
+    let mut points = vec![];
+    for psize in sweep.sizes() {
+        if past_deadline() {
+            break;
+        }
+        opt.psize = psize;
+        report_info(&opt, &format!("\nPayload size {}:", psize));
+        if let Some(result) = run_test(&opt)? {
+            points.push(SweepPoint {
+                psize,
+                frame_size: psize + ETH_HEADER_SIZE,
+                rate_bps: result.rate_bps,
+                pps: result.pkts as f32 / result.secs,
+                dropped_pct: result.dropped_pct,
+            });
         }
     }
+    let max_rate = points.iter().map(|p| p.rate_bps).fold(0.0, f32::max);
+    let sweep = Sweep {
+        kind: "sweep",
+        points,
+        unit: opt.units.pick(max_rate),
+    };
+    clear_progress();
+    print_report(&opt, &sweep);
+    Ok(())
+}
 
-    fn next(self) -> Self {
-        Self {
-            id: self.id,
-            cnt: self.cnt + 1,
-            last: false,
+// How long --sniff listens
+const SNIFF_SECS: u64 = 5;
+
+// Sources counted per ethertype by --sniff, past this it's "many"
+const MAX_SNIFF_SOURCES: usize = 64;
+
+// Frames seen by --sniff, by ethertype (after any VLAN tags)
+#[derive(Debug, Serialize)]
+struct Sniff {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    ifname: String,
+    secs: f32,
+    configured: u16, // --ethertype
+    total: u64,
+    ethertypes: Vec<SniffStat>,
+}
+
+#[derive(Debug, Serialize)]
+struct SniffStat {
+    ethertype: u16,
+    frames: u64,
+    tagged: u64, // Carried VLAN tags
+    sources: usize,
+    sources_capped: bool, // More than MAX_SNIFF_SOURCES
+}
+
+impl fmt::Display for Sniff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sniffed {} for {:.1}s (diagnostic, not a measurement): {} frames",
+            self.ifname, self.secs, self.total
+        )?;
+        for e in &self.ethertypes {
+            write!(
+                f,
+                "\nEther Type {:x}{}{}: {} frames",
+                e.ethertype,
+                ethertype_label(e.ethertype),
+                if e.ethertype == self.configured {
+                    " (configured)"
+                } else {
+                    ""
+                },
+                e.frames
+            )?;
+            if e.tagged > 0 {
+                write!(f, ", {} VLAN tagged", e.tagged)?;
+            }
+            let plural = if e.sources == 1 { "" } else { "s" };
+            match e.sources_capped {
+                true => write!(f, ", over {} sources", e.sources)?,
+                false => write!(f, ", {} source{}", e.sources, plural)?,
+            }
         }
+        if self.total == 0 {
+            write!(f, "\nNo frames at all, check the link and the interface")?;
+        } else if !self
+            .ethertypes
+            .iter()
+            .any(|e| e.ethertype == self.configured)
+        {
+            write!(
+                f,
+                "\nNo frames of the configured Ether Type {:x}, check the sender's --ethertype",
+                self.configured
+            )?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct Tracker {
+// Frame and byte counts of a --raw-count stream, everything that arrived
+// until a gap of RX_TIMEOUT
+struct RawCount {
     begin: Instant,
-    total_bytes: u64,
-    last_ptr: usize,
+    last: Instant, // Latest frame
+    pkts: u64,
+    bytes: u64,
     last_rep: Instant,
-    pkts: Vec<(Instant, u64, u64)>, // (timestamp, id, size)
+    rep_pkts: u64, // At the last interval report
+    rep_bytes: u64,
+    peak_pps: f32,
 }
 
-impl Tracker {
-    pub fn new() -> Self {
+impl RawCount {
+    fn new() -> Self {
+        let now = Instant::now();
         Self {
-            begin: Instant::now(),
-            last_rep: Instant::now(),
-            last_ptr: 0,
-            total_bytes: 0,
-            pkts: vec![],
+            begin: now,
+            last: now,
+            pkts: 0,
+            bytes: 0,
+            last_rep: now,
+            rep_pkts: 0,
+            rep_bytes: 0,
+            peak_pps: 0.0,
         }
     }
 
-    fn insert(&mut self, id: &Id, len: u64) {
-        if let Some(last) = self.pkts.last() {
-            if last.1 > id.cnt {
-                eprintln!("Out of order recv!");
-            }
-        }
-        self.total_bytes += len;
-        self.pkts.push((Instant::now(), id.cnt, len));
+    fn record(&mut self, len: usize) {
+        self.last = Instant::now();
+        self.pkts += 1;
+        self.bytes += len as u64;
     }
 
-    fn report_rx(&mut self) {
+    fn interval(&mut self) -> Option<Report> {
         let since_last = self.last_rep.elapsed().as_secs_f32();
-        if since_last >= 1.0 {
-            let since_begin = self.begin.elapsed().as_secs_f32();
-            let chunk = &self.pkts[self.last_ptr..];
-            let bytes: u64 = chunk.iter().map(|p| p.2).sum();
-
-            let cur_rate = ((8 * bytes) as f32) / since_last;
+        if since_last < 1.0 {
+            return None;
+        }
+        let pkts = self.pkts - self.rep_pkts;
+        let bytes = self.bytes - self.rep_bytes;
+        self.peak_pps = self.peak_pps.max(pkts as f32 / since_last);
 
-            let id_diff = chunk.last().unwrap().1 - chunk[0].1 + 1;
-            let dropped = id_diff - chunk.len() as u64;
-            let percent = (dropped as f32 / id_diff as f32) * 100.0;
+        let mut report = Report::new(Kind::Interval, Dir::Rx, 0);
+        report.end = self.begin.elapsed().as_secs_f32();
+        report.start = report.end - since_last;
+        report.pkts = pkts;
+        report.rate_bps = (8 * bytes) as f32 / since_last;
+        report.bytes = Some(bytes);
 
-            println!(
-                "Sec: {:.2}-{:.2}, Recv: {}/{} pkts, Dropped: {:.2}%, Rate: {:.2} Mbps",
-                since_begin - since_last,
-                since_begin,
-                chunk.len(),
-                id_diff,
-                percent,
-                cur_rate / 1_000_000.0
-            );
+        self.last_rep = Instant::now();
+        self.rep_pkts = self.pkts;
+        self.rep_bytes = self.bytes;
+        Some(report)
+    }
 
-            self.last_rep = Instant::now();
-            self.last_ptr = self.pkts.len() - 1;
+    // Up to the last frame, like the summaries of l2perf streams
+    fn summary(&self) -> Report {
+        let secs = self.last.duration_since(self.begin).as_secs_f32();
+        let mut report = Report::new(Kind::Summary, Dir::Rx, 0);
+        report.end = secs;
+        report.pkts = self.pkts;
+        report.bytes = Some(self.bytes);
+        // A single frame has no rate
+        if secs > 0.0 {
+            let avg_pps = self.pkts as f32 / secs;
+            report.rate_bps = (8 * self.bytes) as f32 / secs;
+            report.peak_pps = Some(self.peak_pps.max(avg_pps));
+            report.avg_pps = Some(avg_pps);
         }
+        report
     }
+}
 
-    fn report_tx(&mut self) {
-        let since_last = self.last_rep.elapsed().as_secs_f32();
-        if since_last >= 1.0 {
-            let since_begin = self.begin.elapsed().as_secs_f32();
-            let chunk = &self.pkts[self.last_ptr..];
-            let bytes: u64 = chunk.iter().map(|p| p.2).sum();
+// --raw-count: whatever arrives with the ethertype is counted, without
+// looking for an Id. With no sequence numbers or timestamps there are no
+// drops, duplicates or latency, only the frame and bit rates.
+fn raw_count(opt: &Opt) -> Result<(), Error> {
+    let mut raw_opt = opt.clone();
+    // The ethertype's socket hands over payloads, which are what's counted
+    raw_opt.raw_capture = false;
+    raw_opt.rx_threads = 1;
+    let (_, _, mut rx) = open_channel(&opt.ifname, Some(opt.ethertype), &raw_opt)?;
+    report_info(
+        opt,
+        &format!(
+            "Counting Ether Type {:x}{} frames, rates only...",
+            opt.ethertype,
+            ethertype_label(opt.ethertype)
+        ),
+    );
 
-            let cur_rate = ((8 * bytes) as f32) / since_last;
+    let mut count: Option<RawCount> = None;
+    loop {
+        if past_deadline() {
+            if let Some(count) = count {
+                report_summary(opt, count.summary());
+            }
+            return Ok(());
+        }
+        match rx.next() {
+            Ok(frame) => {
+                let count = count.get_or_insert_with(RawCount::new);
+                count.record(frame.len());
+                if let Some(report) = count.interval() {
+                    report_interval(opt, report);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                if let Some(count) = count.take() {
+                    report_summary(opt, count.summary());
+                }
+            }
+            Err(e) => return Err(Error::Receive(e)),
+        }
+    }
+}
 
-            println!(
-                "Sec: {:.2}-{:.2}, Sent: {} pkts, Rate: {:.2} Mbps",
-                since_begin - since_last,
-                since_begin,
-                chunk.len(),
-                cur_rate / 1_000_000.0
-            );
+// --sniff: read every frame on the interface with raw capture and tally the
+// ethertypes, to spot a sender and receiver that disagree on --ethertype
+fn sniff(opt: &Opt) -> Result<(), Error> {
+    let mut raw_opt = opt.clone();
+    raw_opt.raw_capture = true;
+    raw_opt.rx_threads = 1;
+    let (_, _, mut rx) = open_channel(&opt.ifname, Some(opt.ethertype), &raw_opt)?;
+    report_info(
+        opt,
+        &format!(
+            "Sniffing {} for {}s, a diagnostic run, nothing is measured...",
+            opt.ifname, SNIFF_SECS
+        ),
+    );
 
-            self.last_rep = Instant::now();
-            self.last_ptr = self.pkts.len() - 1;
+    let mut seen: BTreeMap<u16, (SniffStat, BTreeSet<MacAddr>)> = BTreeMap::new();
+    let mut total = 0;
+    let begin = Instant::now();
+    let duration = Duration::from_secs(SNIFF_SECS);
+    while begin.elapsed() < duration {
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(Error::Receive(e)),
+        };
+        let field = |off: usize| {
+            frame
+                .get(off..off + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+        let mut off = 12;
+        let mut tagged = false;
+        let mut ethertype = field(off);
+        while let Some(ETHERTYPE_VLAN | ETHERTYPE_QINQ) = ethertype {
+            tagged = true;
+            off += 4;
+            ethertype = field(off);
+        }
+        let ethertype = match ethertype {
+            Some(ethertype) => ethertype,
+            None => continue, // Runt
+        };
+        total += 1;
+        let (stat, sources) = seen.entry(ethertype).or_insert_with(|| {
+            let stat = SniffStat {
+                ethertype,
+                frames: 0,
+                tagged: 0,
+                sources: 0,
+                sources_capped: false,
+            };
+            (stat, BTreeSet::new())
+        });
+        stat.frames += 1;
+        stat.tagged += tagged as u64;
+        if sources.len() < MAX_SNIFF_SOURCES {
+            sources.insert(MacAddr::new(
+                frame[6], frame[7], frame[8], frame[9], frame[10], frame[11],
+            ));
+        } else {
+            stat.sources_capped = true;
         }
     }
 
-    fn report_rx_summary(&self) {
-        let since_begin = self.begin.elapsed().as_secs_f32();
-        let since_end = self.pkts.last().unwrap().0.elapsed().as_secs_f32();
+    let mut ethertypes: Vec<SniffStat> = seen
+        .into_values()
+        .map(|(mut stat, sources)| {
+            stat.sources = sources.len();
+            stat
+        })
+        .collect();
+    ethertypes.sort_by_key(|e| std::cmp::Reverse(e.frames));
+    let sniff = Sniff {
+        kind: "sniff",
+        ifname: opt.ifname.clone(),
+        secs: begin.elapsed().as_secs_f32(),
+        configured: opt.ethertype,
+        total,
+        ethertypes,
+    };
+    clear_progress();
+    print_report(opt, &sniff);
+    Ok(())
+}
 
-        let id_diff = self.pkts.last().unwrap().1 - self.pkts[0].1 + 1;
-        let dropped = id_diff - self.pkts.len() as u64;
-        let percent = (dropped as f32 / id_diff as f32) * 100.0;
+fn main() {
+    since_start();
+    let mut opt = Opt::from_args();
+    if opt.bufferbloat && opt.probe_secs == 0 {
+        opt.probe_secs = BUFFERBLOAT_IDLE_SECS;
+    }
+    // The injected delay is checked against the latency of timestamped frames
+    if opt.inject_delay.is_some() && opt.timestamp_every == 0 {
+        opt.timestamp_every = INJECT_STAMP_EVERY;
+    }
+    // Only raw capture sees more than one ethertype
+    if opt.ethertype_sweep.is_some() || opt.stream_ethertypes.is_some() {
+        opt.raw_capture = true;
+    }
+    if let Some(preset) = opt.preset {
+        opt.ethertype = preset.ethertype();
+        opt.dest = opt.dest.or(Some(preset.dest()));
+        opt.psize = MIN_PAYLOAD as usize;
+    }
+    // Checked against the smallest size, the payload checks only get easier
+    if let Some(sweep) = opt.size_sweep {
+        opt.psize = sweep.min;
+    }
+    // Likewise the smallest of the schedule, the frame buffer gets the largest
+    let mut largest = None;
+    let schedule = opt
+        .schedule
+        .as_ref()
+        .map(|path| load_schedule(path, opt.psize));
+    if let Some(schedule) = schedule.transpose().unwrap_or_else(|e| {
+        clap::Error::with_description(&e.to_string(), clap::ErrorKind::ValueValidation).exit()
+    }) {
+        let sizes = schedule.frames.iter().map(|&(_, size)| size);
+        opt.psize = sizes.clone().min().unwrap();
+        largest = sizes.max();
+        SCHEDULE.set(schedule).ok();
+    }
 
-        let rate_tot = ((8 * self.total_bytes) as f32) / (since_begin - since_end);
+    if let Err(e) = check_opts(&opt) {
+        clap::Error::with_description(&e.to_string(), clap::ErrorKind::ValueValidation).exit();
+    }
+    opt.psize = largest.unwrap_or(opt.psize);
+    if let Some(columns) = &opt.columns {
+        COLUMNS_SHOWN.set(columns.formatters()).ok();
+    }
+    if let Some(label) = &opt.label {
+        LABEL.set(label.clone()).ok();
+    }
+    if opt.raw_numbers {
+        PRECISION.set(Precision::Raw).ok();
+        opt.units = Units::Fixed(Unit::Bps);
+    } else if let Some(places) = opt.precision {
+        PRECISION.set(Precision::Places(places)).ok();
+    }
+    if opt.show_config {
+        show_config(&opt);
+    }
+    if opt.selftest {
+        process::exit(if selftest(&opt) { 0 } else { 1 });
+    }
 
-        println!(
-            "Summary:\nSec: 0.00-{:.2}, Recv: {}/{} pkts, Dropped: {:.2}%, Rate: {:.2} Mbps",
-            since_begin - since_end,
-            self.pkts.len(),
-            id_diff,
-            percent,
-            rate_tot / 1_000_000.0
-        );
+    if let Some(path) = &opt.import {
+        if let Err(e) = import_results(&opt, path) {
+            e.exit();
+        }
+        return;
+    }
+    let create = |path: &Path, source| Error::Io {
+        what: format!("create {}", path.display()),
+        source,
+    };
+    if let Some(path) = &opt.export {
+        if let Err(e) = open_export(path) {
+            create(path, e).exit();
+        }
+    }
+    if let Some(path) = &opt.plot_data {
+        if let Err(e) = open_plot_data(&opt, path) {
+            create(path, e).exit();
+        }
+    }
+    if opt.durable {
+        start_sync_thread();
+    }
+    if let Some(dir) = &opt.output_dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            create(dir, e).exit();
+        }
     }
+    if let Some(path) = &opt.result_socket {
+        open_result_socket(path);
+    }
+    install_pause_signals();
 
-    fn report_tx_summary(&self) {
-        let since_begin = self.begin.elapsed().as_secs_f32();
+    if let Some(port) = opt.metrics_port {
+        if let Err(source) = serve_metrics(&opt, port) {
+            Error::Io {
+                what: format!("serve metrics on port {}", port),
+                source,
+            }
+            .exit();
+        }
+    }
 
-        let rate_tot = ((8 * self.total_bytes) as f32) / since_begin;
+    if let Some(addr) = opt.control.filter(|_| opt.rx) {
+        if let Err(source) = serve_control(&opt, addr) {
+            Error::Io {
+                what: format!("open the control channel on {}", addr),
+                source,
+            }
+            .exit();
+        }
+    }
 
-        println!(
-            "Summary:\nSec: 0.00-{:.2}, Sent: {} pkts, Rate: {:.2} Mbps",
-            since_begin,
-            self.pkts.len(),
-            rate_tot / 1_000_000.0
+    if has_net_raw() == Some(false) {
+        eprintln!(
+            "Warning: missing the CAP_NET_RAW capability, opening the channel will likely fail"
         );
     }
+
+    if let Some(secs) = opt.wait_for_link {
+        if let Err(e) = wait_for_link(&opt, Duration::from_secs(secs)) {
+            e.exit();
+        }
+    }
+
+    if let Some(secs) = opt.deadline {
+        start_deadline(secs);
+    }
+    let iperf3 = opt.iperf3_json.then(|| opt.clone());
+    if let Err(e) = run(opt) {
+        e.exit();
+    }
+    if let Some(opt) = iperf3 {
+        print_iperf3(&opt);
+    }
+    if past_deadline() {
+        io::stdout().flush().ok();
+        process::exit(DEADLINE_EXIT);
+    }
 }
 
-fn tx_traffic(tx: &mut Box<dyn datalink::DataLinkSender>, mac_addr_src: MacAddr, opts: Opt) {
-    let mut dat = vec![0; opts.psize + ETH_HEADER_SIZE];
-    let mut packet = MutableEthernetPacket::new(&mut dat).unwrap();
-    packet.set_ethertype(EtherType::new(opts.ethertype));
-    packet.set_source(mac_addr_src);
-    packet.set_destination(opts.dest.unwrap());
+// The mode picked by the options, after the setup in main
+fn run(opt: Opt) -> Result<(), Error> {
+    if opt.sniff {
+        return sniff(&opt);
+    }
+    if opt.raw_count {
+        return raw_count(&opt);
+    }
+    if opt.rx && opt.rx_threads > 1 {
+        return rx_threads(opt);
+    }
+    if opt.rx {
+        let (_, _, mut rx) = open_channel(&opt.ifname, Some(opt.ethertype), &opt)?;
+        return rx_traffic(&mut rx, opt, None).map(|_| ());
+    }
 
-    let mut rng = rand::thread_rng();
+    check_source_macs(&opt)?;
+    if opt.handshake {
+        handshake(&opt)?;
+    }
 
-    let begin = Instant::now();
-    let dur = Duration::from_secs(opts.tsecs);
-    let resolution = Duration::from_millis(10);
+    if let Some(sweep) = opt.size_sweep {
+        return size_sweep(opt, sweep);
+    }
 
-    let mut id = Id::new(rng.gen());
+    let mut results = vec![];
+    for run in 1..=opt.repeat {
+        if past_deadline() {
+            break;
+        }
+        if opt.repeat > 1 {
+            report_info(&opt, &format!("\nRun {}/{}:", run, opt.repeat));
+        }
+        results.extend(run_test(&opt)?);
+    }
 
-    let mut tracker = Tracker::new();
-    let mut buf = [0; 32];
+    if opt.repeat > 1 && results.is_empty() {
+        eprintln!("No run completed, nothing to aggregate");
+    } else if opt.repeat > 1 {
+        let mut aggregate = Aggregate::new(&results);
+        aggregate.unit = opt.units.pick(aggregate.rate_mean_bps);
+        clear_progress();
+        print_report(&opt, &aggregate);
+    }
+    Ok(())
+}
 
-    loop {
-        let elapsed = begin.elapsed();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let cur_rate = ((8 * tracker.total_bytes) as f32) / (elapsed.as_secs_f32());
+    const SRC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
 
-        if cur_rate > opts.bandwidth * 1_000_000.0 {
-            // TODO: Dynamic sleep time calculation?
-            thread::sleep(resolution);
-        } else {
-            bincode::serialize_into(&mut buf[..], &id).unwrap();
-            packet.set_payload(&buf);
-            tx.send_to(packet.packet(), None).unwrap().unwrap();
-            tracker.insert(&id, (opts.psize) as u64);
-            id = id.next();
+    fn rx_opts(args: &[&str]) -> Opt {
+        Opt::from_iter(["l2perf", "-r"].iter().chain(args))
+    }
+
+    fn id(cnt: Seq, last: bool) -> Id {
+        Id {
+            last,
+            ..Id::new(0xabcd, cnt)
         }
+    }
+
+    // An Id payload padded to `len` bytes
+    fn payload(id: &Id, len: usize) -> Vec<u8> {
+        let mut payload = wire().serialize(id).unwrap();
+        payload.resize(len, 0);
+        payload
+    }
 
-        tracker.report_tx();
+    // A whole frame as raw capture delivers it, with `tags` of (TPID, VLAN id)
+    fn raw_frame(tags: &[(u16, u16)], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend(SRC);
+        for &(tpid, vid) in tags {
+            frame.extend(tpid.to_be_bytes());
+            frame.extend(vid.to_be_bytes());
+        }
+        frame.extend(ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
 
-        if elapsed > dur {
-            // Inform done
-            id.last = true;
-            bincode::serialize_into(&mut buf[..], &id).unwrap();
-            packet.set_payload(&buf);
-            tx.send_to(packet.packet(), None).unwrap().unwrap();
-            break;
+    fn cnt_of(parsed: &ParsedFrame) -> Seq {
+        match &parsed.body {
+            Body::Id(id) => id.cnt,
+            body => panic!("expected an Id, got {:?}", body),
         }
     }
 
-    tracker.report_tx_summary();
-}
+    #[test]
+    fn id_wire_layout() {
+        let sent = Id {
+            last: true,
+            stamped: true,
+            ts_ns: 0x0102_0304_0506_0708,
+            ..Id::new(0x1122_3344, 0x5566_7788_99aa_bbcc)
+        };
+        let bytes = wire().serialize(&sent).unwrap();
+        assert_eq!(Id::size(), 23);
+        assert_eq!(bytes.len(), Id::size());
+        assert_eq!(bytes[0..4], 0x1122_3344u32.to_le_bytes());
+        assert_eq!(bytes[4..12], 0x5566_7788_99aa_bbccu64.to_le_bytes());
+        assert_eq!(bytes[12..15], [1, 0, 1]);
+        assert_eq!(bytes[15..23], 0x0102_0304_0506_0708u64.to_le_bytes());
 
-fn rx_traffic(rx: &mut Box<dyn datalink::DataLinkReceiver>, opts: Opt) {
-    let mut trackers = HashMap::new();
+        let got = Id::parse(&bytes, 0).unwrap();
+        assert_eq!(got.id, sent.id);
+        assert_eq!(got.cnt, sent.cnt);
+        assert!(got.last && !got.probe && got.stamped);
+        assert_eq!(got.ts_ns, sent.ts_ns);
+    }
 
-    println!("Accepting Ether Type {:x}...", opts.ethertype);
+    #[test]
+    fn id_parse_trailing_payload() {
+        let exact = payload(&id(7, false), Id::size());
+        assert_eq!(Id::parse(&exact, 0).unwrap().cnt, 7);
+        let mut padded = payload(&id(7, false), 1500);
+        padded[Id::size()..].fill(0xee);
+        assert_eq!(Id::parse(&padded, 0).unwrap().cnt, 7);
+        // At an offset, with payload on both sides
+        let mut shifted = vec![0xee; 10];
+        shifted.extend(&padded);
+        assert_eq!(Id::parse(&shifted, 10).unwrap().cnt, 7);
+    }
 
-    loop {
-        match rx.next() {
-            Ok(packet_raw) => {
-                let len = packet_raw.len();
+    #[test]
+    fn id_parse_malformed() {
+        let short = payload(&id(7, false), Id::size() - 1);
+        assert!(matches!(
+            Id::parse(&short, 0),
+            Err(Error::MalformedFrame(22))
+        ));
+        let full = payload(&id(7, false), Id::size());
+        assert!(matches!(
+            Id::parse(&full, 1),
+            Err(Error::MalformedFrame(23))
+        ));
+        assert!(Id::parse(&full, usize::MAX).is_err());
+        let mut bad_flag = full;
+        bad_flag[ID_LAST_OFFSET] = 2;
+        assert!(matches!(
+            Id::parse(&bad_flag, 0),
+            Err(Error::MalformedFrame(_))
+        ));
+    }
 
-                let id: Id = bincode::deserialize(&packet_raw).unwrap();
-                let tracker = trackers.entry(id.id).or_insert_with(Tracker::new);
+    #[test]
+    fn parse_layer3() {
+        let opts = rx_opts(&[]);
+        let frame = payload(&id(5, false), 100);
+        let parsed = parse_frame(&frame, &opts, true).unwrap();
+        assert_eq!(cnt_of(&parsed), 5);
+        assert_eq!(parsed.len, 100);
+        assert_eq!(
+            (parsed.vlan, parsed.src, parsed.ethertype),
+            (None, None, None)
+        );
+    }
 
-                if tracker.total_bytes == 0 {
-                    println!("\nNew incoming traffic:");
-                }
+    #[test]
+    fn parse_sampled() {
+        let opts = rx_opts(&[]);
+        let frame = payload(&id(5, false), 100);
+        let parsed = parse_frame(&frame, &opts, false).unwrap();
+        assert!(matches!(parsed.body, Body::Skipped));
+        assert_eq!(parsed.len, 100);
+        // The sentinel is decoded either way
+        let frame = payload(&id(9, true), 100);
+        assert_eq!(cnt_of(&parse_frame(&frame, &opts, false).unwrap()), 9);
+    }
 
-                tracker.report_rx();
+    #[test]
+    fn parse_training_and_hello() {
+        let opts = rx_opts(&[]);
+        let mut frame = TRAIN_MAGIC.to_vec();
+        frame.resize(100, 0);
+        let parsed = parse_frame(&frame, &opts, true).unwrap();
+        assert!(matches!(parsed.body, Body::Training));
 
-                if id.last {
-                    tracker.report_rx_summary();
-                    trackers.remove(&id.id);
-                    continue;
-                }
-                tracker.insert(&id, len as u64);
-            }
-            Err(e) if matches!(e.kind(), ErrorKind::TimedOut) => {
-                // Handle if the last packet was dropped
-                for t in trackers.values() {
-                    t.report_rx_summary();
-                }
-                trackers.clear();
-            }
-            Err(e) => {
-                panic!("An error occurred while reading: {}", e);
+        let hello = Hello::new(CAP_PROBE, 0, true, MacAddr::from(SRC));
+        let frame = raw_frame(&[], 0x7380, &wire().serialize(&hello).unwrap());
+        let opts = rx_opts(&["--raw-capture"]);
+        match parse_frame(&frame, &opts, false).unwrap().body {
+            Body::Hello(got) => {
+                assert_eq!((got.caps, got.reply, got.mac), (CAP_PROBE, true, SRC))
             }
+            body => panic!("expected a Hello, got {:?}", body),
         }
     }
-}
 
-fn main() {
-    let opt = Opt::from_args();
+    #[test]
+    fn parse_layer3_malformed() {
+        let opts = rx_opts(&[]);
+        assert!(matches!(
+            parse_frame(&[0; 10], &opts, true),
+            Err(Error::MalformedFrame(10))
+        ));
+    }
 
-    let interface = datalink::interfaces()
-        .into_iter()
-        .find(|iface| iface.name == opt.ifname)
-        .expect("Network interface not found");
+    #[test]
+    fn parse_raw_untagged() {
+        let opts = rx_opts(&["--raw-capture"]);
+        let frame = raw_frame(&[], 0x7380, &payload(&id(3, false), 46));
+        let parsed = parse_frame(&frame, &opts, true).unwrap();
+        assert_eq!(cnt_of(&parsed), 3);
+        assert_eq!(parsed.len, 46);
+        assert_eq!(parsed.vlan, None);
+        assert_eq!(parsed.src, Some(MacAddr::from(SRC)));
+        assert_eq!(parsed.ethertype, Some(0x7380));
+    }
 
-    let mut config: Config = Default::default();
+    #[test]
+    fn parse_raw_vlan() {
+        let opts = rx_opts(&["--raw-capture"]);
+        let tags = [(ETHERTYPE_VLAN, 0x2064)]; // PCP 1, VLAN 100
+        let frame = raw_frame(&tags, 0x7380, &payload(&id(3, false), 46));
+        let parsed = parse_frame(&frame, &opts, true).unwrap();
+        assert_eq!(cnt_of(&parsed), 3);
+        assert_eq!(parsed.len, 46);
+        assert_eq!(parsed.vlan, Some(100));
+    }
 
-    if opt.rx {
-        config.channel_type = ChannelType::Layer3(opt.ethertype);
-        config.read_timeout = Some(Duration::from_secs(2));
+    #[test]
+    fn parse_raw_qinq() {
+        let opts = rx_opts(&["--raw-capture"]);
+        let tags = [(ETHERTYPE_QINQ, 200), (ETHERTYPE_VLAN, 100)];
+        let frame = raw_frame(&tags, 0x7380, &payload(&id(3, false), 46));
+        let parsed = parse_frame(&frame, &opts, true).unwrap();
+        assert_eq!(cnt_of(&parsed), 3);
+        assert_eq!(parsed.len, 46);
+        // The outer tag is the one reported
+        assert_eq!(parsed.vlan, Some(200));
+        assert_eq!(parsed.ethertype, Some(0x7380));
     }
 
-    let (mut tx, mut rx) = match datalink::channel(&interface, config) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("Unhandled channel type"),
-        Err(e) => panic!(
-            "An error occurred when creating the datalink channel: {}",
-            e
-        ),
-    };
+    #[test]
+    fn parse_raw_other_ethertypes() {
+        let opts = rx_opts(&["--raw-capture", "--ethertype", "88b5"]);
+        let frame = raw_frame(&[], 0x0800, &payload(&id(3, false), 46));
+        assert!(matches!(
+            parse_frame(&frame, &opts, true),
+            Err(Error::WrongEthertype(0x0800))
+        ));
+        let tags = [(ETHERTYPE_VLAN, 100)];
+        let frame = raw_frame(&tags, 0x0800, &payload(&id(3, false), 46));
+        assert!(matches!(
+            parse_frame(&frame, &opts, true),
+            Err(Error::WrongEthertype(0x0800))
+        ));
+        let frame = raw_frame(&[], 0x88b5, &payload(&id(3, false), 46));
+        assert_eq!(
+            parse_frame(&frame, &opts, true).unwrap().ethertype,
+            Some(0x88b5)
+        );
+    }
 
-    if opt.rx {
-        rx_traffic(&mut rx, opt);
-    } else {
-        tx_traffic(&mut tx, interface.mac.unwrap(), opt);
+    #[test]
+    fn parse_raw_truncated_header() {
+        let opts = rx_opts(&["--raw-capture"]);
+        assert!(matches!(
+            parse_frame(&[0; 13], &opts, true),
+            Err(Error::MalformedFrame(13))
+        ));
+        // A tag with nothing after it
+        let frame = raw_frame(&[(ETHERTYPE_VLAN, 100)], 0x7380, &[]);
+        assert!(matches!(
+            parse_frame(&frame[..16], &opts, true),
+            Err(Error::MalformedFrame(16))
+        ));
+        // Headers alone, no room for the Id
+        let frame = raw_frame(&[], 0x7380, &[]);
+        assert!(matches!(
+            parse_frame(&frame, &opts, true),
+            Err(Error::MalformedFrame(0))
+        ));
+    }
+
+    #[test]
+    fn parse_raw_trailing_payload() {
+        let opts = rx_opts(&["--raw-capture"]);
+        let exact = raw_frame(&[], 0x7380, &payload(&id(3, false), Id::size()));
+        let parsed = parse_frame(&exact, &opts, true).unwrap();
+        assert_eq!((cnt_of(&parsed), parsed.len), (3, Id::size()));
+        let padded = raw_frame(&[], 0x7380, &payload(&id(3, false), 1500));
+        let parsed = parse_frame(&padded, &opts, true).unwrap();
+        assert_eq!((cnt_of(&parsed), parsed.len), (3, 1500));
+    }
+
+    #[test]
+    fn parse_id_offset() {
+        let opts = rx_opts(&["--id-offset", "8"]);
+        let mut frame = vec![0xee; 8];
+        frame.extend(payload(&id(4, true), 100));
+        assert_eq!(cnt_of(&parse_frame(&frame, &opts, false).unwrap()), 4);
+    }
+
+    #[test]
+    fn seq_wrap() {
+        assert_eq!(seq_span(5, 5), 1);
+        assert_eq!(seq_span(5, 9), 5);
+        assert_eq!(seq_span(u64::MAX - 1, 1), 4);
+        assert_eq!(seq_span(u64::MAX, u64::MAX), 1);
+        assert!(seq_before(5, 6));
+        assert!(!seq_before(6, 5));
+        assert!(!seq_before(5, 5));
+        assert!(seq_before(u64::MAX, 0));
+        assert!(!seq_before(0, u64::MAX));
+        assert!(seq_before(u64::MAX - 10, 10));
+    }
+
+    fn check(lowest: Seq, highest: Seq, cnt: Seq) -> Result<(), String> {
+        let mut tracker = Tracker::new(0xabcd);
+        tracker.lowest = Some(lowest);
+        tracker.highest = Some(highest);
+        check_sequence(&tracker, &id(cnt, false))
+    }
+
+    #[test]
+    fn check_sequence_in_order() {
+        assert_eq!(check_sequence(&Tracker::new(0xabcd), &id(7, false)), Ok(()));
+        assert_eq!(check(10, 12, 13), Ok(()));
+        assert_eq!(check(u64::MAX - 1, u64::MAX, 0), Ok(()));
+    }
+
+    #[test]
+    fn check_sequence_duplicate() {
+        assert_eq!(check(10, 12, 12), Err("duplicate frame 12".to_string()));
+        assert_eq!(check(10, 12, 10), Err("duplicate frame 10".to_string()));
+        assert_eq!(
+            check(u64::MAX - 1, 1, u64::MAX),
+            Err(format!("duplicate frame {}", u64::MAX))
+        );
+    }
+
+    #[test]
+    fn check_sequence_out_of_order() {
+        assert_eq!(
+            check(10, 12, 9),
+            Err("out of order frame 9, expected 13".to_string())
+        );
+        assert_eq!(
+            check(0, 1, u64::MAX),
+            Err(format!("out of order frame {}, expected 2", u64::MAX))
+        );
+    }
+
+    #[test]
+    fn check_sequence_lost() {
+        assert_eq!(
+            check(10, 12, 15),
+            Err("lost frames 13-14, got 15".to_string())
+        );
+        assert_eq!(
+            check(u64::MAX - 2, u64::MAX - 1, 1),
+            Err(format!("lost frames {}-0, got 1", u64::MAX))
+        );
+    }
+
+    // Load a schedule file of `text`, named uniquely per test
+    fn load(name: &str, text: &str) -> Result<Schedule, Error> {
+        let path = std::env::temp_dir().join(format!("l2perf-{}-{}", process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let schedule = load_schedule(&path, 64);
+        std::fs::remove_file(&path).ok();
+        schedule
+    }
+
+    fn schedule_err(name: &str, text: &str) -> String {
+        match load(name, text) {
+            Err(Error::Config(e)) => e,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn schedule_lines() {
+        let text = "# offset size\n0 100\n\n  0.5,200\n1.5\n";
+        let schedule = load("lines", text).unwrap();
+        assert_eq!(
+            schedule.frames,
+            [
+                (Duration::ZERO, 100),
+                (Duration::from_millis(500), 200),
+                (Duration::from_millis(1500), 64),
+            ]
+        );
+        // One average gap after the last frame
+        assert_eq!(schedule.lap, Duration::from_millis(2250));
+    }
+
+    #[test]
+    fn schedule_single_frame() {
+        let schedule = load("single", "0\n").unwrap();
+        assert_eq!(schedule.frames, [(Duration::ZERO, 64)]);
+        assert_eq!(schedule.lap, Duration::from_millis(1));
+        assert_eq!(
+            load("single-late", "2\n").unwrap().lap,
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn schedule_errors() {
+        assert!(schedule_err("empty", "# nothing\n\n").contains("lists no frames"));
+        assert!(
+            schedule_err("offset", "0\nsoon\n").ends_with("line 2: offset: invalid float literal")
+        );
+        assert!(schedule_err("size", "0 big\n").contains("line 1: size: "));
+        assert!(schedule_err("negative", "-1\n").ends_with("line 1: offsets can't be negative"));
+        assert!(schedule_err("nan", "NaN\n").ends_with("line 1: offsets can't be negative"));
+        assert!(
+            schedule_err("fields", "0 64 1\n").ends_with("line 1: expected an offset and a size")
+        );
+        assert!(
+            schedule_err("back", "1\n0.5\n").ends_with("line 2: offsets must not go back in time")
+        );
+        let missing = load_schedule(Path::new("/nonexistent/l2perf-schedule"), 64);
+        assert!(
+            matches!(missing, Err(Error::Config(e)) if e.starts_with("Can't read the schedule"))
+        );
     }
 }